@@ -0,0 +1,183 @@
+//! Maintain an archive file that accumulates all rows of regularly downloaded exports.
+//!
+//! Next to the archive, a manifest keeps the checksums of all exports that were merged into it,
+//! so the same export is never applied twice.
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Could not read from '{}'", path.display())]
+    Read {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Could not write '{}'", path.display())]
+    Write {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error(transparent)]
+    Merge(#[from] crate::merge::Error),
+}
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Outcome {
+    /// The amount of rows whose key wasn't in the archive before.
+    pub added: usize,
+    /// The amount of archived rows that were replaced by rows with different content.
+    pub replaced: usize,
+    /// The exports that were merged into the archive.
+    pub ingested: Vec<PathBuf>,
+    /// The exports that were skipped as they were merged into the archive before.
+    pub skipped: Vec<PathBuf>,
+}
+
+/// Return the path of the manifest that belongs to the archive at `archive`.
+pub fn manifest_path(archive: &Path) -> PathBuf {
+    let mut file_name = archive.file_name().unwrap_or_default().to_owned();
+    file_name.push(".manifest");
+    archive.with_file_name(file_name)
+}
+
+/// Merge all `exports` into the `archive` file using `key_columns` and `options`, like [`merge()`](crate::merge()) would,
+/// unless they were merged before, and replace the archive atomically.
+///
+/// The archive is written with `options.delimiter` so that it can be read back as input, ignoring `options.output`.
+/// It is created if it doesn't exist yet.
+///
+/// The manifest is replaced after the archive, so if the process ends in between, the exports are merged again next time,
+/// which leaves the archive as it is as their rows are already in it. The other way around, their rows would be lost.
+pub fn add(
+    archive: &Path,
+    exports: &[PathBuf],
+    key_columns: &[&str],
+    options: crate::merge::Options,
+) -> Result<Outcome, Error> {
+    let manifest_path = manifest_path(archive);
+    let mut manifest = match std::fs::read_to_string(&manifest_path) {
+        Ok(manifest) => manifest,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            return Err(Error::Read {
+                path: manifest_path,
+                source: err,
+            })
+        }
+    };
+
+    let mut out = Outcome::default();
+    let mut inputs = Vec::new();
+    for path in exports {
+        let data = std::fs::read(path).map_err(|err| Error::Read {
+            path: path.clone(),
+            source: err,
+        })?;
+        let checksum = sha1_smol::Sha1::from(&data).digest().to_string();
+        if manifest
+            .lines()
+            .any(|line| line.split(' ').next() == Some(checksum.as_str()))
+        {
+            out.skipped.push(path.clone());
+            continue;
+        }
+        manifest.push_str(&format!("{checksum} {}\n", path.display()));
+        out.ingested.push(path.clone());
+        inputs.push(crate::merge::Input::new(path, std::io::Cursor::new(data)));
+    }
+    if inputs.is_empty() {
+        return Ok(out);
+    }
+
+    let archived_keys = match std::fs::read(archive) {
+        Ok(data) => {
+            // The archive may have rows with the same key if it was edited by hand, so count its keys by merging it alone.
+            let num_keys = crate::merge(
+                Some(crate::merge::Input::new(archive, data.as_slice())),
+                key_columns,
+                std::io::sink(),
+                crate::merge::Options {
+                    report_conflicts: false,
+                    provenance: false,
+                    ..options.clone()
+                },
+            )?
+            .num_records;
+            inputs.insert(
+                0,
+                crate::merge::Input::new(archive, std::io::Cursor::new(data)),
+            );
+            Some(num_keys)
+        }
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => {
+            return Err(Error::Read {
+                path: archive.into(),
+                source: err,
+            })
+        }
+    };
+
+    let mut merged = tempfile(archive)?;
+    let mut writer = std::io::BufWriter::new(merged.as_file_mut());
+    let outcome = crate::merge(
+        inputs,
+        key_columns,
+        &mut writer,
+        crate::merge::Options {
+            output: crate::Dialect {
+                delimiter: options.delimiter,
+                ..Default::default()
+            },
+            report_conflicts: true,
+            ..options
+        },
+    )?;
+    std::io::Write::flush(&mut writer).map_err(|err| Error::Write {
+        path: archive.into(),
+        source: err,
+    })?;
+    drop(writer);
+    // Each key of the archive is still in the merged output.
+    out.added = outcome.num_records - archived_keys.unwrap_or_default();
+    out.replaced = outcome
+        .conflicts
+        .iter()
+        .filter(|c| {
+            archived_keys.is_some()
+                && c.previous.input == 0
+                && c.current.input != 0
+                && c.kept_current
+        })
+        .count();
+    persist(merged, archive)?;
+
+    let mut new_manifest = tempfile(&manifest_path)?;
+    std::io::Write::write_all(new_manifest.as_file_mut(), manifest.as_bytes()).map_err(|err| {
+        Error::Write {
+            path: manifest_path.clone(),
+            source: err,
+        }
+    })?;
+    persist(new_manifest, &manifest_path)?;
+    Ok(out)
+}
+
+/// Create a temporary file next to `path` so that it can be renamed to `path` atomically.
+fn tempfile(path: &Path) -> Result<tempfile::NamedTempFile, Error> {
+    let dir = path
+        .parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    tempfile::NamedTempFile::new_in(dir).map_err(|err| Error::Write {
+        path: path.into(),
+        source: err,
+    })
+}
+
+fn persist(file: tempfile::NamedTempFile, path: &Path) -> Result<(), Error> {
+    file.persist(path).map_err(|err| Error::Write {
+        path: path.into(),
+        source: err.error,
+    })?;
+    Ok(())
+}
@@ -0,0 +1,543 @@
+//! Defaults for command-line arguments that are read from a configuration file.
+use crate::options;
+use anyhow::{bail, Context};
+use clap::{parser::ValueSource, ValueEnum};
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The names of configuration files that are used if they are in the working directory.
+const FILE_NAMES: [&str; 2] = ["stool.toml", "stool.ron"];
+
+/// The contents of a configuration file, with a section per subcommand and named profiles of such sections.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub merge_accounts: MergeAccounts,
+    pub merge: Merge,
+    pub profiles: BTreeMap<String, Profile>,
+}
+
+/// A named set of values that take precedence over the ones outside of any profile.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Profile {
+    pub merge_accounts: MergeAccounts,
+    pub merge: Merge,
+}
+
+/// The arguments of `merge-accounts`, named like their flags, but with underscores.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MergeAccounts {
+    #[serde(alias = "max_distance_seconds")]
+    pub max_after_seconds: Option<u64>,
+    pub max_before_seconds: Option<u64>,
+    pub auto_window: Option<u64>,
+    pub matching: Option<String>,
+    pub plausible_rates: Option<String>,
+    pub reference_rates: Option<PathBuf>,
+    pub on_implausible_amount: Option<String>,
+    pub batch_window_seconds: Option<u64>,
+    pub max_batch_size: Option<usize>,
+    pub batch_tolerance: Option<f64>,
+    pub normalize_if_starts_with: Option<String>,
+    pub thousands_separator: Option<char>,
+    pub decimal_separator: Option<char>,
+    pub notes: Option<PathBuf>,
+    pub overrides: Option<PathBuf>,
+    pub stripe_date_column: Option<String>,
+    pub stripe_time_column: Option<String>,
+    pub stripe_date_time_column: Option<String>,
+    pub stripe_date_time_format: Option<Vec<String>>,
+    pub stripe_delimiter: Option<char>,
+    pub stripe_amount_column: Option<String>,
+    pub stripe_currency_column: Option<String>,
+    pub stripe_id_column: Option<String>,
+    pub stripe_row_id_column: Option<String>,
+    pub github_date_column: Option<String>,
+    pub github_delimiter: Option<char>,
+    pub github_amount_column: Option<String>,
+    pub github_key_column: Option<String>,
+    pub github_id_column: Option<String>,
+    pub on_conflict: Option<String>,
+    pub prefer_column: Option<String>,
+    pub prefer_values: Option<Vec<String>>,
+    pub output_delimiter: Option<char>,
+    pub output_quoting: Option<String>,
+    pub output_line_terminator: Option<String>,
+    pub output_bom: Option<bool>,
+}
+
+/// The arguments of `merge` which aren't positional, named like their flags, but with underscores.
+#[derive(Debug, Default, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Merge {
+    pub delimiter: Option<char>,
+    pub on_conflict: Option<String>,
+    pub prefer_column: Option<String>,
+    pub prefer_values: Option<Vec<String>>,
+    pub provenance: Option<bool>,
+    pub union_columns: Option<bool>,
+    pub max_records_in_memory: Option<usize>,
+    pub sort_type: Option<String>,
+    pub date_format: Option<String>,
+    pub decimal_separator: Option<char>,
+    pub descending: Option<bool>,
+    pub output_delimiter: Option<char>,
+    pub output_quoting: Option<String>,
+    pub output_line_terminator: Option<String>,
+    pub output_bom: Option<bool>,
+}
+
+/// Return the path to the configuration file given in `config_file`, or to the one in the working directory if there is one.
+pub fn discover(config_file: &options::ConfigFile) -> anyhow::Result<Option<PathBuf>> {
+    if let Some(path) = &config_file.config {
+        return Ok(Some(path.clone()));
+    }
+    let found: Vec<_> = FILE_NAMES
+        .iter()
+        .map(Path::new)
+        .filter(|path| path.is_file())
+        .collect();
+    match found.as_slice() {
+        [] => match &config_file.profile {
+            Some(profile) => bail!(
+                "Could not find a configuration file with profile '{profile}', as neither '{}' nor '{}' is in the working directory",
+                FILE_NAMES[0],
+                FILE_NAMES[1]
+            ),
+            None => Ok(None),
+        },
+        [path] => Ok(Some(path.to_path_buf())),
+        _ => bail!(
+            "Found both '{}' and '{}' in the working directory, use --config to pick one",
+            FILE_NAMES[0],
+            FILE_NAMES[1]
+        ),
+    }
+}
+
+/// Read the configuration file at `path` as TOML if it has the `.toml` extension, or as RON otherwise,
+/// with paths in it made relative to its directory.
+pub fn load(path: &Path) -> anyhow::Result<Config> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("Could not read configuration file at '{}'", path.display()))?;
+    let parse_error = || format!("Could not parse configuration file at '{}'", path.display());
+    let mut config: Config = if path.extension().is_some_and(|ext| ext == "toml") {
+        toml::from_str(&data).with_context(parse_error)?
+    } else {
+        ron::Options::default()
+            .with_default_extension(ron::extensions::Extensions::IMPLICIT_SOME)
+            .from_str(&data)
+            .with_context(parse_error)?
+    };
+    let dir = path.parent().unwrap_or(Path::new(""));
+    for merge_accounts in std::iter::once(&mut config.merge_accounts).chain(
+        config
+            .profiles
+            .values_mut()
+            .map(|profile| &mut profile.merge_accounts),
+    ) {
+        for path in [
+            &mut merge_accounts.notes,
+            &mut merge_accounts.overrides,
+            &mut merge_accounts.reference_rates,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            *path = dir.join(&*path);
+        }
+    }
+    Ok(config)
+}
+
+/// The sections of a configuration file to apply in order, each with the name of the profile it is in, if any.
+pub type Sections<T> = Vec<(Option<String>, T)>;
+
+/// Load the configuration file found by [`discover()`], and return its path along with the sections picked by `section`,
+/// the one of the profile in `config_file` first.
+pub fn sections<T>(
+    config_file: &options::ConfigFile,
+    section: impl Fn(Profile) -> T,
+) -> anyhow::Result<(Option<PathBuf>, Sections<T>)> {
+    let Some(path) = discover(config_file)? else {
+        return Ok((None, Vec::new()));
+    };
+    let Config {
+        merge_accounts,
+        merge,
+        mut profiles,
+    } = load(&path)?;
+    let mut out = Vec::new();
+    if let Some(name) = &config_file.profile {
+        let Some(profile) = profiles.remove(name) else {
+            let known: Vec<_> = profiles.keys().map(|name| format!("'{name}'")).collect();
+            bail!(
+                "There is no profile '{name}' in '{}'{}",
+                path.display(),
+                if known.is_empty() {
+                    String::new()
+                } else {
+                    format!(", only {}", known.join(", "))
+                }
+            );
+        };
+        out.push((Some(name.clone()), section(profile)));
+    }
+    out.push((
+        None,
+        section(Profile {
+            merge_accounts,
+            merge,
+        }),
+    ));
+    Ok((Some(path), out))
+}
+
+/// Apply values of a configuration file to arguments that weren't given on the command-line,
+/// and remember where each value came from.
+pub struct Overrides<'a> {
+    matches: Option<&'a clap::ArgMatches>,
+    path: Option<PathBuf>,
+    /// The profile that values are currently taken from.
+    profile: Option<String>,
+    /// The arguments whose value was taken from the file, along with the profile it was in.
+    from_file: Vec<(&'static str, Option<String>)>,
+}
+
+impl<'a> Overrides<'a> {
+    /// Create an instance for the arguments in `matches` and the configuration file at `path`, if there is one.
+    pub fn new(matches: Option<&'a clap::ArgMatches>, path: Option<PathBuf>) -> Self {
+        Overrides {
+            matches,
+            path,
+            profile: None,
+            from_file: Vec::new(),
+        }
+    }
+
+    /// Return `true` if the argument with `id` was given on the command-line.
+    pub fn is_given(&self, id: &str) -> bool {
+        self.matches
+            .and_then(|matches| matches.value_source(id))
+            .is_some_and(|source| source == ValueSource::CommandLine)
+    }
+
+    /// Return `true` if the argument with `id` was given on the command-line or already set from the file.
+    fn is_taken(&self, id: &str) -> bool {
+        self.is_given(id) || self.from_file.iter().any(|(taken, _)| *taken == id)
+    }
+
+    /// Set `value` to `file_value` if there is one and the argument with `id` wasn't given on the command-line
+    /// or set by a profile.
+    pub fn set<T>(&mut self, id: &'static str, value: &mut T, file_value: Option<T>) {
+        if let Some(file_value) = file_value {
+            if !self.is_taken(id) {
+                *value = file_value;
+                self.from_file.push((id, self.profile.clone()));
+            }
+        }
+    }
+
+    /// Like [`set()`](Self::set()), but convert `file_value` with `parse` first and fail with an error naming `id` if that fails.
+    pub fn set_parsed<T, F>(
+        &mut self,
+        id: &'static str,
+        value: &mut T,
+        file_value: Option<F>,
+        parse: impl FnOnce(F) -> Result<T, String>,
+    ) -> anyhow::Result<()> {
+        if let Some(file_value) = file_value {
+            if !self.is_taken(id) {
+                self.from_file.push((id, self.profile.clone()));
+                *value = parse(file_value).map_err(|err| self.invalid(id, err))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Like [`set_parsed()`](Self::set_parsed()), for a value that is named like on the command-line.
+    fn set_value_enum<T: ValueEnum>(
+        &mut self,
+        id: &'static str,
+        value: &mut T,
+        file_value: Option<String>,
+    ) -> anyhow::Result<()> {
+        self.set_parsed(id, value, file_value, |name| {
+            T::from_str(&name, false).map_err(|_| {
+                let names: Vec<_> = T::value_variants()
+                    .iter()
+                    .filter_map(|variant| variant.to_possible_value())
+                    .map(|variant| format!("'{}'", variant.get_name()))
+                    .collect();
+                format!("'{name}' isn't one of {}", names.join(", "))
+            })
+        })
+    }
+
+    /// Apply the values of `file`, which are in `profile` if it is set, to the arguments of `args`
+    /// that weren't given on the command-line or set by a previous profile.
+    pub fn apply_merge_accounts(
+        &mut self,
+        profile: Option<String>,
+        file: MergeAccounts,
+        args: &mut options::MergeAccounts,
+    ) -> anyhow::Result<()> {
+        self.profile = profile;
+        self.set(
+            "max_after_seconds",
+            &mut args.max_after_seconds,
+            file.max_after_seconds,
+        );
+        self.set(
+            "max_before_seconds",
+            &mut args.max_before_seconds,
+            file.max_before_seconds,
+        );
+        self.set(
+            "auto_window",
+            &mut args.auto_window,
+            file.auto_window.map(Some),
+        );
+        self.set_value_enum("matching", &mut args.matching, file.matching)?;
+        self.set_parsed(
+            "plausible_rates",
+            &mut args.plausible_rates,
+            file.plausible_rates,
+            |rates| options::parse_rates(&rates).map(Some),
+        )?;
+        self.set(
+            "reference_rates",
+            &mut args.reference_rates,
+            file.reference_rates.map(Some),
+        );
+        self.set_value_enum(
+            "on_implausible_amount",
+            &mut args.on_implausible_amount,
+            file.on_implausible_amount,
+        )?;
+        self.set(
+            "batch_window_seconds",
+            &mut args.batch_window_seconds,
+            file.batch_window_seconds.map(Some),
+        );
+        self.set(
+            "max_batch_size",
+            &mut args.max_batch_size,
+            file.max_batch_size,
+        );
+        self.set(
+            "batch_tolerance",
+            &mut args.batch_tolerance,
+            file.batch_tolerance,
+        );
+        self.set(
+            "normalize_if_starts_with",
+            &mut args.normalize_if_starts_with,
+            file.normalize_if_starts_with,
+        );
+        self.set(
+            "thousands_separator",
+            &mut args.thousands_separator,
+            file.thousands_separator,
+        );
+        self.set(
+            "decimal_separator",
+            &mut args.decimal_separator,
+            file.decimal_separator,
+        );
+        self.set("notes", &mut args.notes, file.notes.map(Some));
+        self.set("overrides", &mut args.overrides, file.overrides.map(Some));
+        // A date-time column from the file mustn't undo the separate columns given on the command-line or in a profile.
+        if !self.is_taken("stripe_date_column") && !self.is_taken("stripe_time_column") {
+            self.set(
+                "stripe_date_time_column",
+                &mut args.stripe_date_time_column,
+                file.stripe_date_time_column.map(Some),
+            );
+        }
+        self.set(
+            "stripe_date_column",
+            &mut args.stripe_date_column,
+            file.stripe_date_column,
+        );
+        self.set(
+            "stripe_time_column",
+            &mut args.stripe_time_column,
+            file.stripe_time_column,
+        );
+        self.set(
+            "stripe_date_time_format",
+            &mut args.stripe_date_time_format,
+            file.stripe_date_time_format,
+        );
+        self.set(
+            "stripe_delimiter",
+            &mut args.stripe_delimiter,
+            file.stripe_delimiter,
+        );
+        self.set(
+            "stripe_amount_column",
+            &mut args.stripe_amount_column,
+            file.stripe_amount_column,
+        );
+        self.set(
+            "stripe_currency_column",
+            &mut args.stripe_currency_column,
+            file.stripe_currency_column,
+        );
+        self.set(
+            "stripe_id_column",
+            &mut args.stripe_id_column,
+            file.stripe_id_column.map(Some),
+        );
+        self.set(
+            "stripe_row_id_column",
+            &mut args.stripe_row_id_column,
+            file.stripe_row_id_column,
+        );
+        self.set(
+            "github_date_column",
+            &mut args.github_date_column,
+            file.github_date_column,
+        );
+        self.set(
+            "github_delimiter",
+            &mut args.github_delimiter,
+            file.github_delimiter,
+        );
+        self.set(
+            "github_amount_column",
+            &mut args.github_amount_column,
+            file.github_amount_column,
+        );
+        self.set(
+            "github_key_column",
+            &mut args.github_key_column,
+            file.github_key_column,
+        );
+        self.set(
+            "github_id_column",
+            &mut args.github_id_column,
+            file.github_id_column,
+        );
+        self.apply_conflicts(
+            file.on_conflict,
+            file.prefer_column,
+            file.prefer_values,
+            &mut args.conflicts,
+        )?;
+        self.apply_output(
+            file.output_delimiter,
+            file.output_quoting,
+            file.output_line_terminator,
+            file.output_bom,
+            &mut args.output,
+        )
+    }
+
+    /// Apply the values of `file`, which are in `profile` if it is set, to the arguments of `args`
+    /// that weren't given on the command-line or set by a previous profile.
+    pub fn apply_merge(
+        &mut self,
+        profile: Option<String>,
+        file: Merge,
+        args: &mut options::Merge,
+    ) -> anyhow::Result<()> {
+        self.profile = profile;
+        self.set("delimiter", &mut args.delimiter, file.delimiter.map(Some));
+        self.apply_conflicts(
+            file.on_conflict,
+            file.prefer_column,
+            file.prefer_values,
+            &mut args.conflicts,
+        )?;
+        self.set("provenance", &mut args.provenance, file.provenance);
+        self.set("union_columns", &mut args.union_columns, file.union_columns);
+        self.set(
+            "max_records_in_memory",
+            &mut args.max_records_in_memory,
+            file.max_records_in_memory.map(Some),
+        );
+        self.set_value_enum("sort_type", &mut args.sort.sort_type, file.sort_type)?;
+        self.set(
+            "date_format",
+            &mut args.sort.date_format,
+            file.date_format.map(Some),
+        );
+        self.set(
+            "decimal_separator",
+            &mut args.sort.decimal_separator,
+            file.decimal_separator,
+        );
+        self.set("descending", &mut args.sort.descending, file.descending);
+        self.apply_output(
+            file.output_delimiter,
+            file.output_quoting,
+            file.output_line_terminator,
+            file.output_bom,
+            &mut args.output,
+        )
+    }
+
+    fn apply_conflicts(
+        &mut self,
+        on_conflict: Option<String>,
+        prefer_column: Option<String>,
+        prefer_values: Option<Vec<String>>,
+        args: &mut options::Conflicts,
+    ) -> anyhow::Result<()> {
+        self.set_value_enum("on_conflict", &mut args.on_conflict, on_conflict)?;
+        self.set(
+            "prefer_column",
+            &mut args.prefer_column,
+            prefer_column.map(Some),
+        );
+        self.set("prefer_values", &mut args.prefer_values, prefer_values);
+        Ok(())
+    }
+
+    fn apply_output(
+        &mut self,
+        delimiter: Option<char>,
+        quoting: Option<String>,
+        line_terminator: Option<String>,
+        bom: Option<bool>,
+        args: &mut options::OutputDialect,
+    ) -> anyhow::Result<()> {
+        self.set("output_delimiter", &mut args.output_delimiter, delimiter);
+        self.set_value_enum("output_quoting", &mut args.output_quoting, quoting)?;
+        self.set_value_enum(
+            "output_line_terminator",
+            &mut args.output_line_terminator,
+            line_terminator,
+        )?;
+        self.set("output_bom", &mut args.output_bom, bom);
+        Ok(())
+    }
+
+    /// Fail with an error naming the argument with `id` if `check` failed.
+    pub fn check(&self, id: &str, check: Result<(), String>) -> anyhow::Result<()> {
+        check.map_err(|err| self.invalid(id, err))
+    }
+
+    fn invalid(&self, id: &str, err: String) -> anyhow::Error {
+        anyhow::anyhow!("Invalid value for {}: {err}", self.name(id))
+    }
+
+    /// Return how the user would refer to the argument with `id`, as flag or as key in the configuration file.
+    pub fn name(&self, id: &str) -> String {
+        let profile = self
+            .from_file
+            .iter()
+            .find_map(|(taken, profile)| (*taken == id).then_some(profile));
+        match (&self.path, profile) {
+            (Some(path), Some(Some(profile))) => {
+                format!("'{id}' of profile '{profile}' in '{}'", path.display())
+            }
+            (Some(path), Some(None)) => format!("'{id}' in '{}'", path.display()),
+            _ => format!("--{}", id.replace('_', "-")),
+        }
+    }
+}
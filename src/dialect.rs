@@ -0,0 +1,86 @@
+//! Control the shape of CSV output, to make it palatable to spreadsheet applications of all kinds.
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Cannot use '{0}' as output delimiter")]
+    InvalidDelimiter(char),
+    #[error("Could not write byte order mark")]
+    WriteBom(#[from] std::io::Error),
+}
+
+/// Determines which fields are quoted.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Quoting {
+    /// Quote fields only if they contain delimiters, quotes or line terminators.
+    #[default]
+    Necessary,
+    /// Quote all fields.
+    Always,
+    /// Quote all fields that aren't numbers.
+    NonNumeric,
+    /// Never quote fields, even if that produces invalid CSV.
+    Never,
+}
+
+/// The characters to end each line with.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LineTerminator {
+    /// `\n`
+    #[default]
+    Lf,
+    /// `\r\n`
+    CrLf,
+}
+
+/// The CSV flavor to write.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Dialect {
+    /// The character to separate fields with. It must be ASCII.
+    pub delimiter: char,
+    pub quoting: Quoting,
+    pub line_terminator: LineTerminator,
+    /// If `true`, start the output with a UTF-8 byte order mark which helps some spreadsheet applications to detect the encoding.
+    pub bom: bool,
+}
+
+impl Default for Dialect {
+    fn default() -> Self {
+        Dialect {
+            delimiter: ',',
+            quoting: Quoting::default(),
+            line_terminator: LineTerminator::default(),
+            bom: false,
+        }
+    }
+}
+
+impl Dialect {
+    /// Return the delimiter as byte, or fail if it isn't ASCII.
+    pub fn delimiter_byte(&self) -> Result<u8, Error> {
+        u8::try_from(self.delimiter)
+            .ok()
+            .filter(u8::is_ascii)
+            .ok_or(Error::InvalidDelimiter(self.delimiter))
+    }
+
+    /// Create a CSV writer for `out` which writes in this dialect, after writing the byte order mark if configured.
+    pub fn writer<W: std::io::Write>(&self, mut out: W) -> Result<csv::Writer<W>, Error> {
+        let delimiter = self.delimiter_byte()?;
+        if self.bom {
+            out.write_all(b"\xEF\xBB\xBF")?;
+        }
+        Ok(csv::WriterBuilder::new()
+            .delimiter(delimiter)
+            .quote_style(match self.quoting {
+                Quoting::Necessary => csv::QuoteStyle::Necessary,
+                Quoting::Always => csv::QuoteStyle::Always,
+                Quoting::NonNumeric => csv::QuoteStyle::NonNumeric,
+                Quoting::Never => csv::QuoteStyle::Never,
+            })
+            .terminator(match self.line_terminator {
+                LineTerminator::Lf => csv::Terminator::Any(b'\n'),
+                LineTerminator::CrLf => csv::Terminator::CRLF,
+            })
+            .from_writer(out))
+    }
+}
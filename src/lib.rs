@@ -9,6 +9,11 @@ pub use merge::function::merge;
 
 pub mod sle;
 
+pub mod archive;
+
+pub mod dialect;
+pub use dialect::Dialect;
+
 /// Transform typical numbers as encountered in GitHub CSV and stripe CSV and change their thousands and decimal separators.
 pub fn normalize_number(
     number: impl Into<Vec<u8>>,
@@ -35,3 +40,57 @@ pub fn normalize_number(
     }
     number
 }
+
+/// Parse typical amounts as encountered in GitHub CSV and stripe CSV, like `$1,000.00` or `€8,75`, ignoring currency symbols.
+///
+/// A `,` or `.` followed by exactly two digits is taken as the decimal point, and any other as thousands separator.
+/// Numbers with another amount of decimals, like `0.921`, are thus misread, and should be parsed with [`parse_rate()`].
+pub fn parse_number(number: &[u8]) -> Option<f64> {
+    let number: String = normalize_number(number.trim_ascii(), '_', '.')
+        .into_iter()
+        .filter(|b| b.is_ascii_digit() || *b == b'.' || *b == b'-')
+        .map(char::from)
+        .collect();
+    number.parse().ok()
+}
+
+/// Parse a rate like `0.921` or `149,532`, whose only `,` or `.` is the decimal point no matter how many digits follow it.
+///
+/// Rates with more than one separator are rejected, as no thousands separators are guessed.
+pub fn parse_rate(rate: &[u8]) -> Option<f64> {
+    let rate = std::str::from_utf8(rate.trim_ascii()).ok()?;
+    if rate.matches(['.', ',']).count() > 1 {
+        return None;
+    }
+    rate.replace(',', ".").parse().ok()
+}
+
+/// Format `number` with `decimals` fractional digits, and with `thousands_separator` between each group of three digits of the whole part.
+pub fn format_number(
+    number: f64,
+    decimals: usize,
+    thousands_separator: char,
+    decimal_separator: char,
+) -> String {
+    let formatted = format!("{:.*}", decimals, number.abs());
+    let (whole, fraction) = formatted
+        .split_once('.')
+        .map_or((formatted.as_str(), None), |(whole, fraction)| {
+            (whole, Some(fraction))
+        });
+    let mut out = String::new();
+    if number < 0.0 && formatted.bytes().any(|b| b.is_ascii_digit() && b != b'0') {
+        out.push('-');
+    }
+    for (idx, digit) in whole.chars().enumerate() {
+        if idx > 0 && (whole.len() - idx) % 3 == 0 {
+            out.push(thousands_separator);
+        }
+        out.push(digit);
+    }
+    if let Some(fraction) = fraction {
+        out.push(decimal_separator);
+        out.push_str(fraction);
+    }
+    out
+}
@@ -1,108 +1,1038 @@
 use crate::options::Args;
 use anyhow::Context;
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 use std::path::PathBuf;
 
+mod config;
+
 mod options {
     use std::path::PathBuf;
 
     #[derive(Debug, clap::Parser)]
     #[structopt(name = "stool", about = "A tool to help dealing with sponsor data")]
+    #[allow(clippy::large_enum_variant)]
     pub enum Args {
         /// Merge github activity and stripe information to learn about the sponsored values in local currency if that's not USD.
-        MergeAccounts {
-            /// The amount of seconds a stripe account transaction may be away from the best candidate in the sponsor list to be considered.
-            #[clap(long, short = 'm', default_value = "5")]
-            max_distance_seconds: u64,
-            /// Fields whose value starts with one of these characters will have their thousands- and comma-separators normalized.
-            #[clap(long, default_value = "€$")]
-            normalize_if_starts_with: String,
-            /// The separator between bigger numbers, like 1.000 or 1,000.
-            #[clap(long, default_value = ".")]
-            thousands_separator: char,
-            /// The separator to partition whole part of a number from the fractional part.
-            #[clap(long, default_value = ",")]
-            decimal_separator: char,
-            /// The path to a `.ron` file which declares matching rules for finding rows to which to add a particular note in an appended "Note" column.
-            #[clap(long, short = 'n')]
-            notes: Option<PathBuf>,
-            /// The non-overlapping CSV files obtained from a GitHub activity CSV export.
-            #[clap(long, short = 'g')]
-            github_activity: Vec<PathBuf>,
-            /// The non-overlapping CSV files obtained from a stripe activity CSV export.
-            #[clap(long, short = 's')]
-            stripe_activity: Vec<PathBuf>,
-        },
+        MergeAccounts(MergeAccounts),
         /// Merge multiple files of the same kind with overlaps together into one stream without overlaps.
         ///
         /// Useful if you download all activity regularly, without fear of loosing older values which might be dropped by stripe
         /// at some point.
-        Merge {
-            #[clap(long, short = 'd')]
-            delimiter: Option<char>,
-            /// The index or name of the column to use as key for merging.
-            ///
-            /// Rows seen later with the key will overwrite those that are seen earlier.
+        Merge(Merge),
+        /// Pair github activity and stripe information like `merge-accounts` once for each window instead of `--max-after-seconds`,
+        /// and print how many rows were paired with each, without writing any CSV.
+        Tune(Tune),
+        /// Work with an archive file that accumulates all rows of regularly downloaded exports.
+        Archive {
+            #[clap(subcommand)]
+            cmd: Archive,
+        },
+    }
+
+    #[derive(Debug, clap::Args)]
+    pub struct MergeAccounts {
+        /// The amount of seconds a stripe account transaction may be booked after the best candidate in the sponsor list to be considered.
+        #[clap(long, short = 'm', alias = "max-distance-seconds", default_value = "5")]
+        pub max_after_seconds: u64,
+        /// The amount of seconds a stripe account transaction may be booked before the best candidate in the sponsor list to be considered,
+        /// to allow for clock skew and bookings rounded to the previous second.
+        #[clap(long, default_value = "0")]
+        pub max_before_seconds: u64,
+        /// Choose the seconds a stripe transaction may be booked after a sponsor transaction from the lags of the pairs
+        /// which are unambiguous within this many seconds, instead of using `--max-after-seconds`.
+        ///
+        /// The chosen window and the percentiles of the lags it was chosen from are printed.
+        #[clap(long, num_args = 0..=1, default_missing_value = "3600", value_name = "SECONDS")]
+        pub auto_window: Option<u64>,
+        /// How to pair GitHub rows with stripe rows.
+        #[clap(long, value_enum, default_value = "greedy")]
+        pub matching: Matching,
+        /// Explain for each GitHub row which stripe rows were looked at, and why they weren't paired with it.
+        ///
+        /// The explanation is printed to stderr, or written as JSON lines to the given file.
+        #[clap(long, num_args = 0..=1, value_name = "PATH")]
+        pub explain: Option<Option<PathBuf>>,
+        /// The stripe `Net` per GitHub `Processed Amount` which is plausible for a pair, like `0.8..1.0`.
+        ///
+        /// With `--reference-rates`, it is relative to the reference rate of the stripe row's currency, and `0.5..1.5` by default.
+        /// Without, amounts are only judged if it is given, the same for every currency.
+        #[clap(long, value_parser = parse_rates, value_name = "MIN..MAX")]
+        pub plausible_rates: Option<std::ops::RangeInclusive<f64>>,
+        /// The path to a CSV file with a `Currency` and a `Rate` column, the amount of the currency worth one USD.
+        #[clap(long)]
+        pub reference_rates: Option<PathBuf>,
+        /// What to do with pairs whose amounts aren't plausible.
+        #[clap(long, value_enum, default_value = "flag")]
+        pub on_implausible_amount: AmountPolicy,
+        /// Pair stripe rows that remain unpaired with a group of GitHub rows booked at most this many seconds before them,
+        /// whose summed amounts are plausible for the stripe amount, as stripe may book several payments as one.
+        ///
+        /// The rows of each group have the same number in a `Group` column.
+        #[clap(long)]
+        pub batch_window_seconds: Option<u64>,
+        /// The most GitHub rows in a group if `--batch-window-seconds` is given.
+        #[clap(long, default_value = "4")]
+        pub max_batch_size: usize,
+        /// How far the rate implied by the summed amounts of a group may be from the expected rate, relative to it,
+        /// like `0.02` for 2%.
+        ///
+        /// The expected rate is the centre of `--plausible-rates`, relative to the reference rate with `--reference-rates`.
+        /// No groups are formed with stripe rows whose amounts aren't judged.
+        #[clap(long, default_value = "0.02")]
+        pub batch_tolerance: f64,
+        /// Fields whose value starts with one of these characters will have their thousands- and comma-separators normalized.
+        #[clap(long, default_value = "€$")]
+        pub normalize_if_starts_with: String,
+        /// The separator between bigger numbers, like 1.000 or 1,000.
+        #[clap(long, default_value = ".")]
+        pub thousands_separator: char,
+        /// The separator to partition whole part of a number from the fractional part.
+        #[clap(long, default_value = ",")]
+        pub decimal_separator: char,
+        /// The path to a `.ron` file which declares matching rules for finding rows to which to add a particular note in an appended "Note" column.
+        #[clap(long, short = 'n')]
+        pub notes: Option<PathBuf>,
+        /// The path to a `.ron` file which pins GitHub transactions to stripe rows, or marks rows as intentionally unmatched,
+        /// before any other rows are paired.
+        #[clap(long)]
+        pub overrides: Option<PathBuf>,
+        /// The path to a CSV file to write GitHub rows to that were paired with a stripe row.
+        ///
+        /// If this or `--unmatched-github` or `--unmatched-stripe` is given, the rows of each category with a file are written
+        /// there instead of into the combined output on stdout, which keeps the other categories.
+        #[clap(long)]
+        pub matched: Option<PathBuf>,
+        /// The path to a CSV file to write GitHub rows to that weren't paired with any stripe row, along with the reason if an override excluded them.
+        #[clap(long)]
+        pub unmatched_github: Option<PathBuf>,
+        /// The path to a CSV file to write stripe rows to that weren't paired with any GitHub row, along with the reason.
+        #[clap(long)]
+        pub unmatched_stripe: Option<PathBuf>,
+        /// The index or name of the stripe column with the date of a transaction.
+        #[clap(long, default_value = "Date")]
+        pub stripe_date_column: String,
+        /// The index or name of the stripe column with the time of a transaction.
+        #[clap(long, default_value = "Time")]
+        pub stripe_time_column: String,
+        /// The index or name of a stripe column with the date and the time of a transaction, like `Created (UTC)`,
+        /// instead of the `Date` and `Time` columns.
+        #[clap(long, conflicts_with_all = ["stripe_date_column", "stripe_time_column"])]
+        pub stripe_date_time_column: Option<String>,
+        /// A `time` format description to parse the date and time of stripe transactions with, like
+        /// `[year]-[month]-[day] [hour]:[minute]:[second]`. Date-times without an offset are in UTC.
+        ///
+        /// It can be given multiple times to try each format in order. Without it, the format of stripe's
+        /// activity exports with a `Date` and a `Time` column is used.
+        #[clap(long, value_name = "FORMAT")]
+        pub stripe_date_time_format: Vec<String>,
+        /// The delimiter of the stripe activity CSV files.
+        #[clap(long, default_value = ",")]
+        pub stripe_delimiter: char,
+        /// The index or name of the stripe column with the amount received, in the local currency.
+        #[clap(long, default_value = "Net")]
+        pub stripe_amount_column: String,
+        /// The index or name of the stripe column with the currency of the amount received, like `EUR`.
+        #[clap(long, default_value = "Currency")]
+        pub stripe_currency_column: String,
+        /// The index or name of the column with the date and time of a transaction in the GitHub activity exports.
+        #[clap(long, default_value = "Transaction Date")]
+        pub github_date_column: String,
+        /// The delimiter of the GitHub activity CSV files.
+        #[clap(long, default_value = ",")]
+        pub github_delimiter: char,
+        /// The index or name of the column with the amount GitHub processed, in USD.
+        #[clap(long, default_value = "Processed Amount")]
+        pub github_amount_column: String,
+        /// The index or name of the column that identifies a transaction in the GitHub activity exports.
+        #[clap(long, default_value = "Transaction ID")]
+        pub github_key_column: String,
+        /// The index or name of a stripe column with the ID of the GitHub transaction, like a description containing the charge ID.
+        ///
+        /// Rows are paired by ID first, and only the remaining rows are paired by time.
+        #[clap(long)]
+        pub stripe_id_column: Option<String>,
+        /// The index or name of the GitHub column with the ID to look for in `--stripe-id-column`.
+        #[clap(long, default_value = "Transaction ID")]
+        pub github_id_column: String,
+        /// The index or name of the stripe column with the ID of a row, by which overrides may refer to it.
+        #[clap(long, default_value = "id")]
+        pub stripe_row_id_column: String,
+        #[clap(flatten)]
+        pub conflicts: Conflicts,
+        /// The CSV files obtained from a GitHub activity CSV export, which may overlap.
+        #[clap(long, short = 'g')]
+        pub github_activity: Vec<PathBuf>,
+        /// The non-overlapping CSV files obtained from a stripe activity CSV export.
+        #[clap(long, short = 's')]
+        pub stripe_activity: Vec<PathBuf>,
+        #[clap(flatten)]
+        pub output: OutputDialect,
+        #[clap(flatten)]
+        pub config_file: ConfigFile,
+    }
+
+    /// The arguments of `merge-accounts` that have no effect in `tune`, which tries each of its windows and writes no CSV.
+    pub const NOT_FOR_TUNE: [&str; 6] = [
+        "max_after_seconds",
+        "auto_window",
+        "explain",
+        "matched",
+        "unmatched_github",
+        "unmatched_stripe",
+    ];
+
+    #[derive(Debug, clap::Args)]
+    #[clap(
+        mut_arg(NOT_FOR_TUNE[0], |arg| arg.hide(true)),
+        mut_arg(NOT_FOR_TUNE[1], |arg| arg.hide(true)),
+        mut_arg(NOT_FOR_TUNE[2], |arg| arg.hide(true)),
+        mut_arg(NOT_FOR_TUNE[3], |arg| arg.hide(true)),
+        mut_arg(NOT_FOR_TUNE[4], |arg| arg.hide(true)),
+        mut_arg(NOT_FOR_TUNE[5], |arg| arg.hide(true))
+    )]
+    pub struct Tune {
+        /// The seconds a stripe transaction may be booked after a sponsor transaction to try, like `1,10,60`.
+        #[clap(
+            long,
+            value_delimiter = ',',
+            default_value = "1,2,5,10,30,60,300,600,1800,3600"
+        )]
+        pub windows: Vec<u64>,
+        #[clap(flatten)]
+        pub merge_accounts: MergeAccounts,
+    }
+
+    #[derive(Debug, clap::Args)]
+    pub struct ConfigFile {
+        /// The path to a `.toml` or `.ron` file with values for the flags of this command that weren't given,
+        /// like `(merge_accounts: (stripe_delimiter: ';', max_after_seconds: 10))`.
+        ///
+        /// Without it, `stool.toml` or `stool.ron` in the working directory is used if there is one.
+        #[clap(long, value_name = "PATH")]
+        pub config: Option<PathBuf>,
+        /// The name of a profile in the configuration file whose values take precedence over the ones outside of it.
+        #[clap(long)]
+        pub profile: Option<String>,
+    }
+
+    #[derive(Debug, clap::Args)]
+    pub struct Merge {
+        /// The delimiter of the input CSV files.
+        #[clap(long, short = 'd')]
+        pub delimiter: Option<char>,
+        #[clap(flatten)]
+        pub output: OutputDialect,
+        #[clap(flatten)]
+        pub conflicts: Conflicts,
+        /// The path to a CSV file to write each field that differed between rows with the same key to.
+        #[clap(long)]
+        pub conflict_report: Option<PathBuf>,
+        /// Append the columns `Source File` and `Source Line` with the location each row was taken from,
+        /// and `Seen In Files` with the amount of files its key appeared in.
+        #[clap(long)]
+        pub provenance: bool,
+        /// Allow the header lines of the CSV files to differ, and output the union of all columns.
+        ///
+        /// Columns are lined up by name, and rows of files without a column leave it empty.
+        #[clap(long)]
+        pub union_columns: bool,
+        /// Keep at most about this many rows in memory and spill the rest to temporary files, to merge long histories.
+        ///
+        /// The output is the same as without this limit.
+        #[clap(long)]
+        pub max_records_in_memory: Option<usize>,
+        /// The index or name of the column to use as key for merging, or `*` to use the whole row as key.
+        ///
+        /// Rows seen later with the key will overwrite those that are seen earlier, unless `--on-conflict` says otherwise.
+        /// With `*`, only exact duplicates collapse, ignoring whitespace around fields.
+        pub key_column: String,
+        #[clap(flatten)]
+        pub sort: Sort,
+        /// The index or name of the column to use for sorting the output.
+        pub sort_column: String,
+        /// One or more CSV files to merge - they must have the same shape and a header, unless `--union-columns` is set.
+        pub csv_file: Vec<PathBuf>,
+        #[clap(flatten)]
+        pub config_file: ConfigFile,
+    }
+
+    #[derive(Debug, clap::Subcommand)]
+    pub enum Archive {
+        /// Merge new exports into the archive file and replace it atomically.
+        ///
+        /// Exports that were added before are skipped, as known by their checksum in the `.manifest` file next to the archive.
+        Add {
+            /// The delimiter of the archive and the exports.
+            #[clap(long, short = 'd', default_value = ",")]
+            delimiter: char,
+            #[clap(flatten)]
+            conflicts: Conflicts,
+            /// Allow the header lines of the exports to differ from the archive, and keep the union of all columns.
+            #[clap(long)]
+            union_columns: bool,
+            /// The index or name of the column to use as key for merging, or `*` to use the whole row as key.
             key_column: String,
-            /// The index or name of the column to use for sorting the output.
+            #[clap(flatten)]
+            sort: Sort,
+            /// The index or name of the column to use for sorting the archive.
             sort_column: String,
-            /// One or more CSV files to merge - they must have the same shape and a header.
+            /// The archive file, which is created if it doesn't exist.
+            archive: PathBuf,
+            /// One or more exports to merge into the archive.
+            #[clap(required = true)]
             csv_file: Vec<PathBuf>,
         },
     }
+
+    #[derive(Debug, Clone, clap::Args)]
+    pub struct Conflicts {
+        /// What to do if a key is seen again with a row of different content.
+        #[clap(long, value_enum, default_value = "keep-last")]
+        pub on_conflict: ConflictPolicy,
+        /// The index or name of the column to decide by if `--on-conflict prefer` is used.
+        #[clap(long, required_if_eq("on_conflict", "prefer"))]
+        pub prefer_column: Option<String>,
+        /// The values of `--prefer-column`, from least to most preferred, like `pending,settled,refunded`.
+        #[clap(long, value_delimiter = ',', required_if_eq("on_conflict", "prefer"))]
+        pub prefer_values: Vec<String>,
+    }
+
+    impl From<Conflicts> for stool::merge::ConflictPolicy {
+        fn from(
+            Conflicts {
+                on_conflict,
+                prefer_column,
+                prefer_values,
+            }: Conflicts,
+        ) -> Self {
+            match on_conflict {
+                ConflictPolicy::KeepLast => stool::merge::ConflictPolicy::KeepLast,
+                ConflictPolicy::KeepFirst => stool::merge::ConflictPolicy::KeepFirst,
+                ConflictPolicy::Error => stool::merge::ConflictPolicy::Error,
+                ConflictPolicy::Prefer => stool::merge::ConflictPolicy::PreferByColumn {
+                    column: prefer_column.expect("clap or validation makes it required"),
+                    values: prefer_values,
+                },
+            }
+        }
+    }
+
+    #[derive(Debug, clap::Args)]
+    pub struct Sort {
+        /// How to interpret the values of the sort column.
+        #[clap(long, value_enum, default_value = "text")]
+        pub sort_type: SortType,
+        /// The `time` format description to parse values with if `--sort-type date` is used,
+        /// like `[month repr:long] [day padding:none], [year]`.
+        #[clap(long)]
+        pub date_format: Option<String>,
+        /// The separator to partition whole part of a number from the fractional part if `--sort-type number` is used.
+        #[clap(long, default_value = ".")]
+        pub decimal_separator: char,
+        /// Sort in descending order.
+        #[clap(long)]
+        pub descending: bool,
+    }
+
+    impl Sort {
+        pub fn into_type_and_order(self) -> (stool::merge::SortType, stool::merge::SortOrder) {
+            let Sort {
+                sort_type,
+                date_format,
+                decimal_separator,
+                descending,
+            } = self;
+            (
+                match sort_type {
+                    SortType::Text => stool::merge::SortType::Text,
+                    SortType::Date => stool::merge::SortType::Date {
+                        format: date_format.expect("validation makes it required"),
+                    },
+                    SortType::DateTime => stool::merge::SortType::DateTime,
+                    SortType::Number => stool::merge::SortType::Number { decimal_separator },
+                },
+                if descending {
+                    stool::merge::SortOrder::Descending
+                } else {
+                    stool::merge::SortOrder::Ascending
+                },
+            )
+        }
+    }
+
+    #[derive(Debug, Clone, clap::Args)]
+    pub struct OutputDialect {
+        /// The character to separate fields with in the output, like `;` if `,` is the decimal separator.
+        #[clap(long, default_value = ",")]
+        pub output_delimiter: char,
+        /// Which output fields to put into quotes.
+        #[clap(long, value_enum, default_value = "necessary")]
+        pub output_quoting: Quoting,
+        /// The characters to end each output line with.
+        #[clap(long, value_enum, default_value = "lf")]
+        pub output_line_terminator: LineTerminator,
+        /// Start the output with a UTF-8 byte order mark to help spreadsheet applications detect the encoding.
+        #[clap(long)]
+        pub output_bom: bool,
+    }
+
+    impl From<OutputDialect> for stool::Dialect {
+        fn from(
+            OutputDialect {
+                output_delimiter,
+                output_quoting,
+                output_line_terminator,
+                output_bom,
+            }: OutputDialect,
+        ) -> Self {
+            stool::Dialect {
+                delimiter: output_delimiter,
+                quoting: match output_quoting {
+                    Quoting::Necessary => stool::dialect::Quoting::Necessary,
+                    Quoting::Always => stool::dialect::Quoting::Always,
+                    Quoting::NonNumeric => stool::dialect::Quoting::NonNumeric,
+                    Quoting::Never => stool::dialect::Quoting::Never,
+                },
+                line_terminator: match output_line_terminator {
+                    LineTerminator::Lf => stool::dialect::LineTerminator::Lf,
+                    LineTerminator::CrLf => stool::dialect::LineTerminator::CrLf,
+                },
+                bom: output_bom,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+    pub enum Quoting {
+        /// Quote fields only if they contain delimiters, quotes or line terminators.
+        Necessary,
+        /// Quote all fields.
+        Always,
+        /// Quote all fields that aren't numbers.
+        NonNumeric,
+        /// Never quote fields.
+        Never,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+    pub enum LineTerminator {
+        /// End lines with `\n`.
+        Lf,
+        /// End lines with `\r\n`.
+        CrLf,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+    pub enum ConflictPolicy {
+        /// Rows seen later replace those seen earlier.
+        KeepLast,
+        /// Rows seen earlier are kept.
+        KeepFirst,
+        /// Abort with an error.
+        Error,
+        /// Keep the row whose value in `--prefer-column` comes later in `--prefer-values`.
+        Prefer,
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+    pub enum Matching {
+        /// Each GitHub row takes the closest stripe row within the maximum distance that wasn't taken yet.
+        Greedy,
+        /// Pair as many rows as possible with the smallest sum of distances, and print the pairs that differ from greedy matching.
+        Optimal,
+    }
+
+    impl From<Matching> for stool::merge_accounts::Matching {
+        fn from(value: Matching) -> Self {
+            match value {
+                Matching::Greedy => stool::merge_accounts::Matching::Greedy,
+                Matching::Optimal => stool::merge_accounts::Matching::Optimal,
+            }
+        }
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+    pub enum AmountPolicy {
+        /// Pair them anyway, and print a warning for each.
+        Flag,
+        /// Never pair them.
+        Reject,
+    }
+
+    impl From<AmountPolicy> for stool::merge_accounts::AmountPolicy {
+        fn from(value: AmountPolicy) -> Self {
+            match value {
+                AmountPolicy::Flag => stool::merge_accounts::AmountPolicy::Flag,
+                AmountPolicy::Reject => stool::merge_accounts::AmountPolicy::Reject,
+            }
+        }
+    }
+
+    /// Parse a range of rates like `0.5..1.5`, both ends inclusive.
+    pub fn parse_rates(value: &str) -> Result<std::ops::RangeInclusive<f64>, String> {
+        let (start, end) = value
+            .split_once("..")
+            .ok_or_else(|| format!("expected a range like '0.5..1.5', got '{value}'"))?;
+        let parse = |rate: &str| {
+            rate.trim_start_matches('=')
+                .parse::<f64>()
+                .map_err(|err| format!("invalid rate '{rate}': {err}"))
+        };
+        let (start, end) = (parse(start)?, parse(end)?);
+        if start > end {
+            return Err(format!("the start of '{value}' is larger than its end"));
+        }
+        Ok(start..=end)
+    }
+
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+    pub enum SortType {
+        /// Compare values as they are.
+        Text,
+        /// Parse values as dates with the format given by `--date-format`.
+        Date,
+        /// Parse values as date-time like `2021-12-29 16:07:12 +0100`.
+        DateTime,
+        /// Parse values as numbers, ignoring currency symbols and thousands separators.
+        Number,
+    }
 }
 
 fn main() -> anyhow::Result<()> {
-    let args = options::Args::parse();
+    let matches = options::Args::command().get_matches();
+    let args = options::Args::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
     match args {
-        Args::MergeAccounts {
-            github_activity,
-            stripe_activity,
-            normalize_if_starts_with,
-            thousands_separator,
-            decimal_separator,
-            notes,
-            max_distance_seconds,
-        } => stool::merge_accounts(
-            into_read(github_activity)?,
-            into_read(stripe_activity)?,
-            std::io::BufWriter::new(std::io::stdout()),
-            stool::merge_accounts::Options {
-                max_distance_seconds,
-                number_markers: normalize_if_starts_with,
-                thousands_separator,
-                decimal_separator,
-                notes,
-                ..Default::default()
-            },
-        )?,
-        Args::Merge {
-            delimiter,
-            key_column,
-            sort_column,
-            csv_file,
-        } => stool::merge(
-            into_read(csv_file)?,
-            &[&key_column],
-            std::io::BufWriter::new(std::io::stdout()),
-            stool::merge::Options {
-                delimiter: delimiter.unwrap_or(','),
+        Args::MergeAccounts(mut args) => {
+            let options = configured_merge_accounts_options(
+                &mut args,
+                matches.subcommand_matches("merge-accounts"),
+            )?;
+            let options::MergeAccounts {
+                github_activity,
+                stripe_activity,
+                matched,
+                unmatched_github,
+                unmatched_stripe,
+                explain,
+                overrides,
+                ..
+            } = args;
+            let outcome = stool::merge_accounts(
+                into_read(&github_activity)?,
+                into_read(&stripe_activity)?,
+                sink(matched, unmatched_github, unmatched_stripe)?,
+                options,
+            )?;
+            for conflict in &outcome.github_conflicts {
+                let (kept, dropped) = if conflict.kept_current {
+                    (conflict.current, conflict.previous)
+                } else {
+                    (conflict.previous, conflict.current)
+                };
+                eprintln!(
+                    "GitHub transaction '{}' differed in {}, kept line {} of '{}' and dropped line {} of '{}'",
+                    conflict.key.join(", "),
+                    conflict
+                        .changes
+                        .iter()
+                        .map(|change| format!("'{}'", change.column))
+                        .collect::<Vec<_>>()
+                        .join(", "),
+                    kept.line,
+                    github_activity[kept.input].display(),
+                    dropped.line,
+                    github_activity[dropped.input].display(),
+                );
+            }
+            if let Some(path) = &overrides {
+                for unresolved in &outcome.unresolved_overrides {
+                    eprintln!(
+                        "Override {} in '{}' {} and was ignored",
+                        unresolved.index + 1,
+                        path.display(),
+                        unresolved.reason.as_str()
+                    );
+                }
+            }
+            match explain {
+                Some(Some(path)) => stool::merge_accounts::write_explanations(
+                    &outcome.explanations,
+                    std::io::BufWriter::new(std::fs::File::create(&path).with_context(|| {
+                        format!("Could not create explanation file at '{}'", path.display())
+                    })?),
+                )?,
+                Some(None) => {
+                    for explanation in &outcome.explanations {
+                        eprintln!(
+                            "GitHub transaction '{}' at {}",
+                            explanation.github_key,
+                            format_date_time(explanation.date_time)
+                        );
+                        if explanation.candidates.is_empty() {
+                            eprintln!("  no stripe rows");
+                        }
+                        for candidate in &explanation.candidates {
+                            eprintln!(
+                                "  stripe row of {} at {:+}s: {}",
+                                format_date_time(candidate.date_time),
+                                candidate.offset_seconds,
+                                candidate
+                                    .rejection
+                                    .map_or("paired", |rejection| rejection.as_str())
+                            );
+                        }
+                    }
+                }
+                None => {}
+            }
+            for pair in &outcome.changed_pairs {
+                let stripe_row = |date_time: Option<time::OffsetDateTime>| {
+                    date_time.map_or_else(
+                        || "no stripe row".into(),
+                        |date_time| format!("the stripe row of {}", format_date_time(date_time)),
+                    )
+                };
+                eprintln!(
+                    "GitHub transaction '{}' was paired with {} instead of {}",
+                    pair.github_key,
+                    stripe_row(pair.current),
+                    stripe_row(pair.greedy),
+                );
+            }
+            if outcome.num_greedy_github_rows != 0 {
+                eprintln!(
+                    "Paired {} GitHub transaction(s) greedily as they share candidates with too many others to be paired optimally",
+                    outcome.num_greedy_github_rows
+                );
+            }
+            for pair in &outcome.implausible_pairs {
+                eprintln!(
+                    "GitHub transaction '{}' was paired with the stripe row of {} whose amount implies an implausible rate of {:.4}",
+                    pair.github_key,
+                    format_date_time(pair.date_time),
+                    pair.rate,
+                );
+            }
+            if let Some(window) = &outcome.auto_window {
+                if window.num_pairs == 0 {
+                    eprintln!(
+                        "Kept the window of {}s after GitHub transactions as no pair was unambiguous",
+                        window.max_after_seconds
+                    );
+                } else {
+                    let percentiles: Vec<_> = window
+                        .lag_percentiles
+                        .iter()
+                        .map(|(percentile, lag)| format!("p{percentile} {lag}s"))
+                        .collect();
+                    eprintln!(
+                        "Chose a window of {}s after GitHub transactions from the lags of {} unambiguous pair(s): {}",
+                        window.max_after_seconds,
+                        window.num_pairs,
+                        percentiles.join(", ")
+                    );
+                }
+            }
+        }
+        Args::Tune(mut args) => {
+            let matches = matches.subcommand_matches("tune");
+            let given = config::Overrides::new(matches, None);
+            if let Some(id) = options::NOT_FOR_TUNE.iter().find(|id| given.is_given(id)) {
+                anyhow::bail!(
+                    "{} can't be used with `tune`, which tries each of `--windows` and writes no CSV",
+                    given.name(id)
+                );
+            }
+            let options = configured_merge_accounts_options(&mut args.merge_accounts, matches)?;
+            let (github, stripe) = (
+                read_all(&args.merge_accounts.github_activity)?,
+                read_all(&args.merge_accounts.stripe_activity)?,
+            );
+            println!(
+                "{:>10} {:>8} {:>16} {:>16} {:>9}",
+                "Window [s]", "Matched", "Unmatched GitHub", "Unmatched Stripe", "Ambiguous"
+            );
+            for window in args.windows {
+                let outcome = stool::merge_accounts(
+                    github
+                        .iter()
+                        .map(|(path, data)| stool::merge::Input::new(path, data.as_slice())),
+                    stripe
+                        .iter()
+                        .map(|(path, data)| stool::merge::Input::new(path, data.as_slice())),
+                    stool::merge_accounts::Sink::<std::io::Sink>::Split {
+                        matched: None,
+                        unmatched_github: None,
+                        unmatched_stripe: None,
+                        rest: None,
+                    },
+                    stool::merge_accounts::Options {
+                        max_after_seconds: window,
+                        auto_window_seconds: None,
+                        explain: false,
+                        ..options.clone()
+                    },
+                )?;
+                println!(
+                    "{:>10} {:>8} {:>16} {:>16} {:>9}",
+                    window,
+                    outcome.num_matched,
+                    outcome.num_unmatched_github_rows,
+                    outcome.num_unmatched_stripe_rows,
+                    outcome.num_ambiguous_github_rows
+                );
+            }
+        }
+        Args::Merge(mut args) => {
+            let (path, sections) = config::sections(&args.config_file, |profile| profile.merge)?;
+            let mut overrides = config::Overrides::new(matches.subcommand_matches("merge"), path);
+            for (profile, section) in sections {
+                overrides.apply_merge(profile, section, &mut args)?;
+            }
+            check_conflicts(&args.conflicts, &overrides)?;
+            check_sort(&args.sort, &overrides)?;
+            let options::Merge {
+                delimiter,
+                output,
+                conflicts,
+                conflict_report,
+                union_columns,
+                provenance,
+                max_records_in_memory,
+                key_column,
+                sort,
                 sort_column,
-            },
-        )
-        .map(|_| ())?,
+                csv_file,
+                config_file: _,
+            } = args;
+            let (sort_type, sort_order) = sort.into_type_and_order();
+            let outcome = stool::merge(
+                into_read(&csv_file)?,
+                &[&key_column],
+                std::io::BufWriter::new(std::io::stdout()),
+                stool::merge::Options {
+                    sort_column,
+                    sort_type,
+                    sort_order,
+                    delimiter: delimiter.unwrap_or(','),
+                    output: output.into(),
+                    conflict_policy: conflicts.into(),
+                    report_conflicts: conflict_report.is_some(),
+                    schema: schema_policy(union_columns),
+                    provenance,
+                    key_mode: key_mode(&key_column),
+                    max_records_in_memory,
+                },
+            )?;
+            if let Some(path) = conflict_report {
+                stool::merge::write_conflict_report(
+                    &outcome.conflicts,
+                    std::fs::File::create(&path).with_context(|| {
+                        format!("Could not create conflict report at '{}'", path.display())
+                    })?,
+                )?;
+            }
+            for (path, missing_columns) in csv_file.iter().zip(&outcome.missing_columns) {
+                if !missing_columns.is_empty() {
+                    eprintln!(
+                        "'{}' was missing column(s): {}",
+                        path.display(),
+                        missing_columns.join(", ")
+                    );
+                }
+            }
+            if outcome.num_conflicts != 0 {
+                eprintln!(
+                    "{} key(s) were seen again with different row content",
+                    outcome.num_conflicts
+                );
+            }
+        }
+        Args::Archive {
+            cmd:
+                options::Archive::Add {
+                    delimiter,
+                    conflicts,
+                    union_columns,
+                    key_column,
+                    sort,
+                    sort_column,
+                    archive,
+                    csv_file,
+                },
+        } => {
+            check_sort(&sort, &config::Overrides::new(None, None))?;
+            let (sort_type, sort_order) = sort.into_type_and_order();
+            let outcome = stool::archive::add(
+                &archive,
+                &csv_file,
+                &[&key_column],
+                stool::merge::Options {
+                    sort_column,
+                    sort_type,
+                    sort_order,
+                    delimiter,
+                    conflict_policy: conflicts.into(),
+                    schema: schema_policy(union_columns),
+                    key_mode: key_mode(&key_column),
+                    ..Default::default()
+                },
+            )?;
+            for path in &outcome.skipped {
+                eprintln!("Skipped '{}' as it was added before", path.display());
+            }
+            println!(
+                "Added {} row(s) and replaced {} row(s) in '{}'",
+                outcome.added,
+                outcome.replaced,
+                archive.display()
+            );
+        }
     };
     Ok(())
 }
 
-fn into_read(file_paths: Vec<PathBuf>) -> anyhow::Result<impl Iterator<Item = impl std::io::Read>> {
+/// Apply the configuration file to the arguments in `args` that aren't in `matches`, and turn them into options.
+fn configured_merge_accounts_options(
+    args: &mut options::MergeAccounts,
+    matches: Option<&clap::ArgMatches>,
+) -> anyhow::Result<stool::merge_accounts::Options> {
+    let (path, sections) = config::sections(&args.config_file, |profile| profile.merge_accounts)?;
+    let mut overrides = config::Overrides::new(matches, path);
+    for (profile, section) in sections {
+        overrides.apply_merge_accounts(profile, section, args)?;
+    }
+    merge_accounts_options(args, &overrides)
+}
+
+/// Turn `args` into options, or fail with an error naming the first invalid argument as known by `overrides`.
+fn merge_accounts_options(
+    args: &options::MergeAccounts,
+    overrides: &config::Overrides<'_>,
+) -> anyhow::Result<stool::merge_accounts::Options> {
+    let ascii = |separator: char| {
+        if separator.is_ascii() {
+            Ok(())
+        } else {
+            Err(format!("'{separator}' isn't an ASCII character"))
+        }
+    };
+    let column = |column: &str| {
+        if column.trim().is_empty() {
+            Err("the column name is empty".to_string())
+        } else {
+            Ok(())
+        }
+    };
+    overrides.check("stripe_delimiter", ascii(args.stripe_delimiter))?;
+    overrides.check("github_delimiter", ascii(args.github_delimiter))?;
+    overrides.check("output_delimiter", ascii(args.output.output_delimiter))?;
+    overrides.check("thousands_separator", ascii(args.thousands_separator))?;
+    overrides.check(
+        "decimal_separator",
+        ascii(args.decimal_separator).and_then(|()| {
+            if args.decimal_separator == args.thousands_separator {
+                Err("it is the same as the thousands separator".into())
+            } else {
+                Ok(())
+            }
+        }),
+    )?;
+    overrides.check(
+        "max_batch_size",
+        if args.max_batch_size < 2 {
+            Err("a group needs at least 2 rows".into())
+        } else {
+            Ok(())
+        },
+    )?;
+    overrides.check(
+        "batch_tolerance",
+        if args.batch_tolerance.is_finite() && args.batch_tolerance >= 0.0 {
+            Ok(())
+        } else {
+            Err("it must be a positive fraction".into())
+        },
+    )?;
+    for (id, value) in [
+        ("stripe_date_column", &args.stripe_date_column),
+        ("stripe_time_column", &args.stripe_time_column),
+        ("stripe_amount_column", &args.stripe_amount_column),
+        ("stripe_currency_column", &args.stripe_currency_column),
+        ("github_date_column", &args.github_date_column),
+        ("github_amount_column", &args.github_amount_column),
+        ("github_key_column", &args.github_key_column),
+        ("github_id_column", &args.github_id_column),
+        ("stripe_row_id_column", &args.stripe_row_id_column),
+    ] {
+        overrides.check(id, column(value))?;
+    }
+    for format in &args.stripe_date_time_format {
+        overrides.check(
+            "stripe_date_time_format",
+            time::format_description::parse(format)
+                .map(|_| ())
+                .map_err(|err| format!("'{format}' is no valid format description: {err}")),
+        )?;
+    }
+    check_conflicts(&args.conflicts, overrides)?;
+
+    let defaults = stool::merge_accounts::Options::default();
+    let (stripe_date_column, stripe_time_column) = match &args.stripe_date_time_column {
+        Some(column) => (column.clone(), None),
+        None => (
+            args.stripe_date_column.clone(),
+            Some(args.stripe_time_column.clone()),
+        ),
+    };
+    Ok(stool::merge_accounts::Options {
+        stripe_date_column,
+        stripe_time_column,
+        stripe_date_time_formats: if args.stripe_date_time_format.is_empty() {
+            defaults.stripe_date_time_formats
+        } else {
+            args.stripe_date_time_format.clone()
+        },
+        stripe_delimiter: args.stripe_delimiter,
+        github_date_column: args.github_date_column.clone(),
+        github_key_column: args.github_key_column.clone(),
+        github_id_column: args.github_id_column.clone(),
+        stripe_id_column: args.stripe_id_column.clone(),
+        stripe_row_id_column: args.stripe_row_id_column.clone(),
+        github_conflict_policy: args.conflicts.clone().into(),
+        github_delimiter: args.github_delimiter,
+        max_before_seconds: args.max_before_seconds,
+        max_after_seconds: args.max_after_seconds,
+        auto_window_seconds: args.auto_window,
+        matching: args.matching.into(),
+        explain: args.explain.is_some(),
+        github_amount_column: args.github_amount_column.clone(),
+        stripe_amount_column: args.stripe_amount_column.clone(),
+        stripe_currency_column: args.stripe_currency_column.clone(),
+        plausible_rates: args.plausible_rates.clone(),
+        reference_rates: args.reference_rates.clone(),
+        on_implausible_amount: args.on_implausible_amount.into(),
+        batch_window_seconds: args.batch_window_seconds,
+        max_batch_size: args.max_batch_size,
+        batch_tolerance: args.batch_tolerance,
+        number_markers: args.normalize_if_starts_with.clone(),
+        decimal_separator: args.decimal_separator,
+        thousands_separator: args.thousands_separator,
+        notes: args.notes.clone(),
+        overrides: args.overrides.clone(),
+        output: args.output.clone().into(),
+    })
+}
+
+/// Fail if `conflicts` lacks the column to prefer by, with an error naming the argument as known by `overrides`.
+fn check_conflicts(
+    conflicts: &options::Conflicts,
+    overrides: &config::Overrides<'_>,
+) -> anyhow::Result<()> {
+    if conflicts.on_conflict != options::ConflictPolicy::Prefer {
+        return Ok(());
+    }
+    overrides.check(
+        "prefer_column",
+        conflicts.prefer_column.as_ref().map(|_| ()).ok_or_else(|| {
+            format!(
+                "it is required if {} is 'prefer'",
+                overrides.name("on_conflict")
+            )
+        }),
+    )
+}
+
+/// Fail if `sort` lacks the format to parse dates with, with an error naming the argument as known by `overrides`.
+fn check_sort(sort: &options::Sort, overrides: &config::Overrides<'_>) -> anyhow::Result<()> {
+    if sort.sort_type != options::SortType::Date {
+        return Ok(());
+    }
+    overrides.check(
+        "date_format",
+        sort.date_format.as_ref().map(|_| ()).ok_or_else(|| {
+            format!(
+                "it is required if {} is 'date'",
+                overrides.name("sort_type")
+            )
+        }),
+    )
+}
+
+fn format_date_time(date_time: time::OffsetDateTime) -> String {
+    date_time
+        .format(gix_date::time::format::ISO8601)
+        .expect("valid format")
+}
+
+/// Write each category to its file if any path is given, or everything to stdout otherwise.
+fn sink(
+    matched: Option<PathBuf>,
+    unmatched_github: Option<PathBuf>,
+    unmatched_stripe: Option<PathBuf>,
+) -> anyhow::Result<stool::merge_accounts::Sink<Box<dyn std::io::Write>>> {
+    let stdout =
+        || -> Box<dyn std::io::Write> { Box::new(std::io::BufWriter::new(std::io::stdout())) };
+    if matched.is_none() && unmatched_github.is_none() && unmatched_stripe.is_none() {
+        return Ok(stool::merge_accounts::Sink::Combined(stdout()));
+    }
+    let create = |path: Option<PathBuf>| -> anyhow::Result<Option<Box<dyn std::io::Write>>> {
+        path.map(|path| -> anyhow::Result<Box<dyn std::io::Write>> {
+            let file = std::fs::File::create(&path)
+                .with_context(|| format!("Could not create '{}'", path.display()))?;
+            Ok(Box::new(std::io::BufWriter::new(file)))
+        })
+        .transpose()
+    };
+    // The categories without a file of their own still go to stdout.
+    let rest = (matched.is_none() || unmatched_github.is_none() || unmatched_stripe.is_none())
+        .then(stdout);
+    Ok(stool::merge_accounts::Sink::Split {
+        matched: create(matched)?,
+        unmatched_github: create(unmatched_github)?,
+        unmatched_stripe: create(unmatched_stripe)?,
+        rest,
+    })
+}
+
+fn key_mode(key_column: &str) -> stool::merge::KeyMode {
+    if key_column == "*" {
+        stool::merge::KeyMode::WholeRow
+    } else {
+        stool::merge::KeyMode::Columns
+    }
+}
+
+fn schema_policy(union_columns: bool) -> stool::merge::SchemaPolicy {
+    if union_columns {
+        stool::merge::SchemaPolicy::Union
+    } else {
+        stool::merge::SchemaPolicy::Strict
+    }
+}
+
+/// Read each of `file_paths` into memory, along with its path.
+fn read_all(file_paths: &[PathBuf]) -> anyhow::Result<Vec<(PathBuf, Vec<u8>)>> {
+    file_paths
+        .iter()
+        .map(|path| {
+            std::fs::read(path)
+                .with_context(|| format!("Could not open CSV file at '{}'", path.display()))
+                .map(|data| (path.clone(), data))
+        })
+        .collect()
+}
+
+fn into_read(
+    file_paths: &[PathBuf],
+) -> anyhow::Result<impl Iterator<Item = stool::merge::Input<impl std::io::Read>>> {
     Ok(file_paths
         .iter()
         .map(|p| {
-            std::fs::read(p)
-                .with_context(|| format!("Could not read from CSV file at '{}'", p.display()))
+            std::fs::File::open(p)
+                .with_context(|| format!("Could not open CSV file at '{}'", p.display()))
+                .map(|file| stool::merge::Input::new(p, file))
         })
         .collect::<Result<Vec<_>, _>>()?
-        .into_iter()
-        .map(std::io::Cursor::new))
+        .into_iter())
 }
@@ -1,3 +1,8 @@
+use crate::Dialect;
+use std::path::PathBuf;
+
+mod external;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("No input was provided")]
@@ -6,6 +11,14 @@ pub enum Error {
     InvalidDelimiter(char),
     #[error(transparent)]
     Csv(#[from] csv::Error),
+    #[error(transparent)]
+    Dialect(#[from] crate::dialect::Error),
+    #[error("Could not create a temporary file to spill rows to")]
+    TempFile(#[source] std::io::Error),
+    #[error("Could not write rows to a temporary file")]
+    WriteSpilledRows(#[source] csv::Error),
+    #[error("Could not read rows back from a temporary file")]
+    ReadSpilledRows(#[source] csv::Error),
     #[error(
         "A {kind} column of index or name '{name}' could not be found in first line of CSV file"
     )]
@@ -14,6 +27,41 @@ pub enum Error {
     SchemaChange { previous: String, current: String },
     #[error("Row in line {line} did not have a column at index {key_column_index}")]
     ColumnMissingInRow { line: u64, key_column_index: usize },
+    #[error("Invalid date format description '{format}'")]
+    InvalidDateFormat {
+        format: String,
+        source: time::error::InvalidFormatDescription,
+    },
+    #[error("Could not parse sort value '{value}' in line {line} as {kind}")]
+    ParseSortValue {
+        line: u64,
+        value: String,
+        kind: &'static str,
+    },
+    #[error("Row in line {current_line} of '{}' conflicts with row in line {previous_line} of '{}' for key '{key}'", current_path.display(), previous_path.display())]
+    Conflict {
+        key: String,
+        previous_path: PathBuf,
+        previous_line: u64,
+        current_path: PathBuf,
+        current_line: u64,
+    },
+}
+
+/// A CSV input along with the path it was read from.
+pub struct Input<R> {
+    /// The path of the input, used to tell where rows came from.
+    pub path: PathBuf,
+    pub read: R,
+}
+
+impl<R: std::io::Read> Input<R> {
+    pub fn new(path: impl Into<PathBuf>, read: R) -> Self {
+        Input {
+            path: path.into(),
+            read,
+        }
+    }
 }
 
 pub struct Outcome {
@@ -23,105 +71,838 @@ pub struct Outcome {
     pub sort_column_index: usize,
     /// The delimiter that was used to write the output with.
     pub delimiter: u8,
+    /// The amount of rows written, without the header line.
+    pub num_records: usize,
+    /// The amount of times a key was seen again with a row of different content.
+    pub num_conflicts: usize,
+    /// Details about each conflict, only filled in if [`Options::report_conflicts`] is set.
+    pub conflicts: Vec<Conflict>,
+    /// For each input, the names of output columns it didn't have, which is only possible with [`SchemaPolicy::Union`].
+    pub missing_columns: Vec<Vec<String>>,
+}
+
+/// Determines how to deal with inputs whose header lines differ.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SchemaPolicy {
+    /// All inputs must have the exact same header line, or [`Error::SchemaChange`] is returned.
+    #[default]
+    Strict,
+    /// The output has the union of all columns, in order of first appearance, and fields are lined up by column name.
+    /// Rows of inputs that don't have a column leave it empty.
+    ///
+    /// Key and sort columns given by index refer to the output columns.
+    Union,
+}
+
+/// Determines which row to keep if a key is seen again with a row of different content.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// Rows seen later replace those seen earlier.
+    #[default]
+    KeepLast,
+    /// Rows seen earlier are kept.
+    KeepFirst,
+    /// Abort the merge with [`Error::Conflict`].
+    Error,
+    /// Keep the row whose value in `column` comes later in `values`, like `["pending", "settled", "refunded"]` for a `Status` column.
+    ///
+    /// Values that aren't listed rank lowest, and the row seen later is kept if both rank the same.
+    PreferByColumn { column: String, values: Vec<String> },
+}
+
+/// Determines what identifies a row when merging.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum KeyMode {
+    /// The values of the given key columns form the key.
+    #[default]
+    Columns,
+    /// The whole row with surrounding whitespace trimmed from each field forms the key, so that only exact duplicates collapse.
+    /// This is useful if there is no reliable key column. Trailing empty fields are ignored, and given key columns are unused.
+    WholeRow,
+}
+
+/// The location of a row within the inputs.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Location {
+    /// The index of the input the row was read from.
+    pub input: usize,
+    /// The line number of the row within its input.
+    pub line: u64,
+}
+
+/// A field whose value differed between two rows with the same key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FieldChange {
+    /// The name of the column the field belongs to.
+    pub column: String,
+    pub previous: String,
+    pub current: String,
+}
+
+/// Two rows with the same key but different content.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Conflict {
+    /// The values of all key columns.
+    pub key: Vec<String>,
+    /// The row that was seen first.
+    pub previous: Location,
+    /// The row that was seen later.
+    pub current: Location,
+    /// Whether the `current` row was kept in favor of the `previous` one.
+    pub kept_current: bool,
+    /// All fields that differ between the two rows.
+    pub changes: Vec<FieldChange>,
+}
+
+/// Determines how values of the sort column are interpreted when sorting.
+///
+/// Empty values sort as the smallest values, so they come first in [`SortOrder::Ascending`] and last in [`SortOrder::Descending`] order.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub enum SortType {
+    /// Compare the raw bytes of each value.
+    #[default]
+    Text,
+    /// Parse values with the given `time` format description, like `[month repr:long] [day padding:none], [year]`.
+    ///
+    /// The format may describe a date, a date with time, or a date with time and offset.
+    /// Times without offset are assumed to be UTC.
+    Date { format: String },
+    /// Parse values as date-time as understood by `gix-date`, like `2021-12-29 16:07:12 +0100`.
+    DateTime,
+    /// Parse values as number, ignoring currency symbols and thousands separators.
+    Number {
+        /// The separator between the whole part of a number and its fractional part.
+        decimal_separator: char,
+    },
+}
+
+/// The order in which to sort the output.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SortOrder {
+    #[default]
+    Ascending,
+    Descending,
 }
 
 #[derive(Clone, Debug)]
 pub struct Options {
     pub sort_column: String,
+    /// How to interpret the values of the `sort_column`.
+    pub sort_type: SortType,
+    pub sort_order: SortOrder,
+    /// The delimiter of the input CSV files.
     pub delimiter: char,
+    /// The CSV flavor to write the output in.
+    pub output: Dialect,
+    /// What to do if a key is seen again with a row of different content.
+    pub conflict_policy: ConflictPolicy,
+    /// If `true`, record each conflict in [`Outcome::conflicts`].
+    pub report_conflicts: bool,
+    /// What to do if the header lines of the inputs differ.
+    pub schema: SchemaPolicy,
+    /// If `true`, append the columns `Source File` and `Source Line` with the location of each output row,
+    /// and `Seen In Files` with the amount of inputs its key appeared in.
+    pub provenance: bool,
+    /// What identifies a row.
+    pub key_mode: KeyMode,
+    /// If set, keep at most about this many rows in memory and spill sorted runs of rows to temporary files,
+    /// which are merged to produce the same output as if everything was kept in memory.
+    pub max_records_in_memory: Option<usize>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            sort_column: String::new(),
+            sort_type: SortType::default(),
+            sort_order: SortOrder::default(),
+            delimiter: ',',
+            output: Dialect::default(),
+            conflict_policy: ConflictPolicy::default(),
+            report_conflicts: false,
+            schema: SchemaPolicy::default(),
+            provenance: false,
+            key_mode: KeyMode::default(),
+            max_records_in_memory: None,
+        }
+    }
+}
+
+/// Write `conflicts` as CSV to `out`, with one row per changed field.
+pub fn write_conflict_report(
+    conflicts: &[Conflict],
+    out: impl std::io::Write,
+) -> Result<(), csv::Error> {
+    let mut out = csv::Writer::from_writer(out);
+    out.write_record([
+        "Key",
+        "Column",
+        "Previous Input",
+        "Previous Line",
+        "Previous Value",
+        "Current Input",
+        "Current Line",
+        "Current Value",
+        "Kept",
+    ])?;
+    for conflict in conflicts {
+        let key = conflict.key.join(", ");
+        for change in &conflict.changes {
+            out.write_record([
+                key.as_str(),
+                &change.column,
+                &conflict.previous.input.to_string(),
+                &conflict.previous.line.to_string(),
+                &change.previous,
+                &conflict.current.input.to_string(),
+                &conflict.current.line.to_string(),
+                &change.current,
+                if conflict.kept_current {
+                    "current"
+                } else {
+                    "previous"
+                },
+            ])?;
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+/// Return the position of `name_or_index` in `headers` or `None` if it wasn't found.
+/// If `name_or_index` is a number, it will be used as number and not as name.
+pub(crate) fn header_idx(name_or_index: &str, headers: &csv::StringRecord) -> Option<usize> {
+    if let Ok(index) = name_or_index.parse() {
+        headers.get(index).map(|_| index)
+    } else {
+        headers.iter().position(|name| name == name_or_index)
+    }
 }
 
 pub(crate) mod function {
-    use crate::merge::{Error, Options, Outcome};
+    use crate::merge::external::Spill;
+    use crate::merge::{
+        header_idx, Conflict, ConflictPolicy, Error, FieldChange, Input, KeyMode, Location,
+        Options, Outcome, SchemaPolicy, SortOrder, SortType,
+    };
+    use std::borrow::Cow;
+    use std::cmp::Ordering;
     use std::collections::BTreeMap;
+    use std::path::PathBuf;
 
     pub fn merge(
-        csv_data: impl IntoIterator<Item = impl std::io::Read>,
+        csv_data: impl IntoIterator<Item = Input<impl std::io::Read>>,
         key_columns: &[&str],
         out: impl std::io::Write,
         Options {
             sort_column,
+            sort_type,
+            sort_order,
             delimiter,
+            output,
+            conflict_policy,
+            report_conflicts,
+            schema,
+            provenance,
+            key_mode,
+            max_records_in_memory,
         }: Options,
     ) -> Result<Outcome, Error> {
         let delimiter = delimiter
             .try_into()
             .map_err(|_| Error::InvalidDelimiter(delimiter))?;
-        let mut data = BTreeMap::new();
-        let mut previous_headers = None::<csv::StringRecord>;
+        let mut rows = match max_records_in_memory {
+            None => Rows::InMemory(BTreeMap::new()),
+            Some(limit) => Rows::Spilled(Spill::new(limit)),
+        };
+        let mut resolver = Resolver {
+            policy: &conflict_policy,
+            report_conflicts,
+            num_conflicts: 0,
+            conflicts: Vec::new(),
+        };
+        let mut headers = None::<csv::StringRecord>;
+        let mut input_headers = Vec::new();
+        let mut paths = Vec::new();
         let mut sort_index = None;
         let mut key_column_indices = None;
+        let mut prefer_by_index = None;
 
-        for csv in csv_data {
+        for (input, Input { path, read }) in csv_data.into_iter().enumerate() {
+            paths.push(path);
             let mut csv = csv::ReaderBuilder::new()
                 .delimiter(delimiter)
                 .has_headers(true)
-                .from_reader(csv);
-            let headers = csv.headers()?;
-            if let Some(previous_headers) = &previous_headers {
-                if previous_headers != headers {
-                    return Err(Error::SchemaChange {
-                        previous: to_string(previous_headers),
-                        current: to_string(headers),
-                    });
+                .from_reader(LineFeeds::new(read));
+            let current_headers = csv.headers()?.clone();
+            let union_to_input = match &mut headers {
+                None => {
+                    headers = Some(current_headers.clone());
+                    None
                 }
-            } else {
-                previous_headers = Some(headers.clone());
-            }
+                Some(headers) => match schema {
+                    SchemaPolicy::Strict => {
+                        if *headers != current_headers {
+                            return Err(Error::SchemaChange {
+                                previous: to_string(headers),
+                                current: to_string(&current_headers),
+                            });
+                        }
+                        None
+                    }
+                    SchemaPolicy::Union => {
+                        for name in &current_headers {
+                            if !headers.iter().any(|existing| existing == name) {
+                                headers.push_field(name);
+                            }
+                        }
+                        let union_to_input: Vec<_> = headers
+                            .iter()
+                            .map(|name| current_headers.iter().position(|n| n == name))
+                            .collect();
+                        union_to_input
+                            .iter()
+                            .enumerate()
+                            .any(|(union_idx, input_idx)| *input_idx != Some(union_idx))
+                            .then_some(union_to_input)
+                    }
+                },
+            };
+            input_headers.push(current_headers);
+            let headers = headers.as_ref().expect("set in first iteration");
 
-            let key_indices = key_columns
-                .iter()
-                .map(|key| {
-                    header_idx(key, headers).ok_or_else(|| Error::MissingColumn {
-                        name: key.to_string(),
-                        kind: "key",
+            let key_indices = match key_mode {
+                KeyMode::Columns => key_columns
+                    .iter()
+                    .map(|key| {
+                        header_idx(key, headers).ok_or_else(|| Error::MissingColumn {
+                            name: key.to_string(),
+                            kind: "key",
+                        })
                     })
-                })
-                .collect::<Result<Vec<_>, _>>()?;
+                    .collect::<Result<Vec<_>, _>>()?,
+                KeyMode::WholeRow => (0..headers.len()).collect(),
+            };
             sort_index = header_idx(&sort_column, headers)
                 .ok_or_else(|| Error::MissingColumn {
                     name: sort_column.clone(),
                     kind: "sort",
                 })?
                 .into();
+            if let ConflictPolicy::PreferByColumn { column, .. } = &conflict_policy {
+                prefer_by_index =
+                    Some(
+                        header_idx(column, headers).ok_or_else(|| Error::MissingColumn {
+                            name: column.clone(),
+                            kind: "conflict",
+                        })?,
+                    );
+            }
+            let context = Context {
+                headers,
+                key_mode,
+                key_indices: &key_indices,
+                prefer_by_index,
+                paths: &paths,
+            };
 
-            for record in csv.into_byte_records() {
-                let record = record?;
-                let mut key = Vec::<u8>::new();
-                for key_index in &key_indices {
-                    key.extend_from_slice(record.get(*key_index).ok_or_else(|| {
-                        Error::ColumnMissingInRow {
-                            line: record.position().expect("present").line(),
-                            key_column_index: *key_index,
+            loop {
+                let mut record = csv::ByteRecord::new();
+                if !csv.read_byte_record(&mut record)? {
+                    break;
+                }
+                let mut position = record.position().expect("present").clone();
+                position.set_line(csv.get_mut().line(position.byte()));
+                record.set_position(Some(position));
+                let record = match &union_to_input {
+                    Some(union_to_input) => reorder(&record, union_to_input),
+                    None => record,
+                };
+                let key = match key_mode {
+                    KeyMode::Columns => {
+                        let mut key = Vec::<u8>::new();
+                        for key_index in &key_indices {
+                            key.extend_from_slice(record.get(*key_index).ok_or_else(|| {
+                                Error::ColumnMissingInRow {
+                                    line: record.position().expect("present").line(),
+                                    key_column_index: *key_index,
+                                }
+                            })?);
                         }
-                    })?);
+                        key
+                    }
+                    KeyMode::WholeRow => normalized_row(&record),
+                };
+                let current = Row {
+                    record,
+                    input,
+                    seen_in_inputs: 1,
+                };
+                match &mut rows {
+                    Rows::InMemory(data) => match data.get_mut(&key) {
+                        Some(previous) => resolver.resolve(&key, previous, current, &context)?,
+                        None => {
+                            data.insert(key, current);
+                        }
+                    },
+                    Rows::Spilled(spill) => spill.push(key, current, |_| Ok(()))?,
                 }
-                data.insert(key, record);
             }
 
             key_column_indices = Some(key_indices);
         }
         let sort_column_index = sort_index.ok_or(Error::NoInput)?;
-        let mut records: Vec<_> = data.values().collect();
-        records.sort_by_key(|record| record.get(sort_column_index));
+        let key_column_indices = key_column_indices.ok_or(Error::NoInput)?;
+        let headers = headers.ok_or(Error::NoInput)?;
+        let date_format = match &sort_type {
+            SortType::Date { format } => {
+                time::format_description::parse(format).map_err(|err| Error::InvalidDateFormat {
+                    format: format.clone(),
+                    source: err,
+                })?
+            }
+            _ => Vec::new(),
+        };
 
-        let headers = previous_headers.ok_or(Error::NoInput)?;
-        let delimiter = b',';
-        let mut out = csv::WriterBuilder::new()
-            .delimiter(delimiter)
-            .from_writer(out);
-        out.write_record(&headers)?;
-        for record in &records {
-            out.write_byte_record(record)?;
+        let delimiter = output.delimiter_byte()?;
+        let mut out = output.writer(out)?;
+        if provenance {
+            let mut headers = headers.clone();
+            headers.push_field("Source File");
+            headers.push_field("Source Line");
+            headers.push_field("Seen In Files");
+            out.write_record(&headers)?;
+        } else {
+            out.write_record(&headers)?;
         }
+        let mut extended = csv::ByteRecord::new();
+        let mut write_row = |row: &Row| -> Result<(), Error> {
+            if row.record.len() < headers.len() || provenance {
+                extended.clone_from(&row.record);
+                while extended.len() < headers.len() {
+                    extended.push_field(&[]);
+                }
+                if provenance {
+                    extended.push_field(paths[row.input].to_string_lossy().as_bytes());
+                    extended.push_field(row.line().to_string().as_bytes());
+                    extended.push_field(row.seen_in_inputs.to_string().as_bytes());
+                }
+                out.write_byte_record(&extended)?;
+            } else {
+                out.write_byte_record(&row.record)?;
+            }
+            Ok(())
+        };
+
+        let num_records = match rows {
+            Rows::InMemory(data) => {
+                let mut records = data
+                    .values()
+                    .map(|row| {
+                        sort_value(&row.record, sort_column_index, &sort_type, &date_format)
+                            .map(|value| (value, row))
+                    })
+                    .collect::<Result<Vec<_>, _>>()?;
+                records.sort_by(|(a, _), (b, _)| match sort_order {
+                    SortOrder::Ascending => a.cmp(b),
+                    SortOrder::Descending => b.cmp(a),
+                });
+                for (_, row) in &records {
+                    write_row(row)?;
+                }
+                records.len()
+            }
+            Rows::Spilled(spill) => {
+                let context = Context {
+                    headers: &headers,
+                    key_mode,
+                    key_indices: &key_column_indices,
+                    prefer_by_index,
+                    paths: &paths,
+                };
+                let sort_rank = |row: &Row| -> Result<SortRank, Error> {
+                    let value =
+                        sort_value(&row.record, sort_column_index, &sort_type, &date_format)?
+                            .map(SortValue::into_owned);
+                    Ok(match sort_order {
+                        SortOrder::Ascending => SortRank::Ascending(value),
+                        SortOrder::Descending => SortRank::Descending(std::cmp::Reverse(value)),
+                    })
+                };
+                let mut sorted = Spill::new(spill.limit());
+                let mut by_key = spill.into_merged(|_| Ok(()))?;
+                let mut current = None::<(Vec<u8>, Row)>;
+                while let Some((key, row)) = by_key.next()? {
+                    match &mut current {
+                        Some((previous_key, previous)) if *previous_key == key => {
+                            resolver.resolve(&key, previous, row, &context)?
+                        }
+                        _ => {
+                            if let Some((key, row)) = current.replace((key, row)) {
+                                sorted.push(key, row, sort_rank)?;
+                            }
+                        }
+                    }
+                }
+                if let Some((key, row)) = current {
+                    sorted.push(key, row, sort_rank)?;
+                }
+                resolver
+                    .conflicts
+                    .sort_by_key(|conflict| (conflict.current.input, conflict.current.line));
+
+                let mut num_records = 0;
+                let mut sorted = sorted.into_merged(sort_rank)?;
+                while let Some((_, row)) = sorted.next()? {
+                    write_row(&row)?;
+                    num_records += 1;
+                }
+                num_records
+            }
+        };
+        out.flush().map_err(csv::Error::from)?;
 
         Ok(Outcome {
             sort_column_index,
-            key_column_indices: key_column_indices.ok_or(Error::NoInput)?,
+            key_column_indices,
             delimiter,
+            num_records,
+            num_conflicts: resolver.num_conflicts,
+            conflicts: resolver.conflicts,
+            missing_columns: input_headers
+                .iter()
+                .map(|input_headers| {
+                    headers
+                        .iter()
+                        .filter(|name| !input_headers.iter().any(|n| n == *name))
+                        .map(ToOwned::to_owned)
+                        .collect()
+                })
+                .collect(),
         })
     }
 
+    /// All rows seen so far.
+    enum Rows {
+        /// Rows deduplicated by key.
+        InMemory(BTreeMap<Vec<u8>, Row>),
+        /// Rows along with their keys, to be deduplicated once all inputs were read.
+        Spilled(Spill<()>),
+    }
+
+    /// Determines the output order of rows if [`Options::max_records_in_memory`] is set, with ties broken by key.
+    #[derive(PartialEq, Eq, PartialOrd, Ord)]
+    enum SortRank {
+        Ascending(Option<SortValue<'static>>),
+        Descending(std::cmp::Reverse<Option<SortValue<'static>>>),
+    }
+
+    /// Everything needed to resolve conflicts between rows.
+    struct Context<'a> {
+        /// The output columns.
+        headers: &'a csv::StringRecord,
+        key_mode: KeyMode,
+        key_indices: &'a [usize],
+        /// The column index of [`ConflictPolicy::PreferByColumn`].
+        prefer_by_index: Option<usize>,
+        /// The paths of all inputs seen so far.
+        paths: &'a [PathBuf],
+    }
+
+    /// Decides which of two rows with the same key to keep, and keeps track of conflicts.
+    struct Resolver<'a> {
+        policy: &'a ConflictPolicy,
+        report_conflicts: bool,
+        num_conflicts: usize,
+        conflicts: Vec<Conflict>,
+    }
+
+    impl Resolver<'_> {
+        /// Merge `current` into `previous`, which was seen earlier with the same `key`.
+        fn resolve(
+            &mut self,
+            key: &[u8],
+            previous: &mut Row,
+            mut current: Row,
+            context: &Context<'_>,
+        ) -> Result<(), Error> {
+            current.seen_in_inputs =
+                previous.seen_in_inputs + usize::from(previous.input != current.input);
+            if same_fields(
+                &previous.record,
+                &current.record,
+                context.key_mode == KeyMode::WholeRow,
+            ) {
+                *previous = current;
+                return Ok(());
+            }
+            self.num_conflicts += 1;
+            let keep_current = self
+                .keep_current(previous, &current, context.prefer_by_index)
+                .ok_or_else(|| Error::Conflict {
+                    key: String::from_utf8_lossy(key).into_owned(),
+                    previous_path: context.paths[previous.input].clone(),
+                    previous_line: previous.line(),
+                    current_path: context.paths[current.input].clone(),
+                    current_line: current.line(),
+                })?;
+            if self.report_conflicts {
+                self.conflicts.push(Conflict {
+                    key: context
+                        .key_indices
+                        .iter()
+                        .map(|idx| lossy(current.record.get(*idx)))
+                        .collect(),
+                    previous: previous.location(),
+                    current: current.location(),
+                    kept_current: keep_current,
+                    changes: field_changes(context.headers, &previous.record, &current.record),
+                });
+            }
+            if keep_current {
+                *previous = current;
+            } else {
+                previous.seen_in_inputs = current.seen_in_inputs;
+            }
+            Ok(())
+        }
+
+        /// Return `true` if `current` should replace `previous`, or `None` if conflicts aren't allowed.
+        fn keep_current(
+            &self,
+            previous: &Row,
+            current: &Row,
+            prefer_by_index: Option<usize>,
+        ) -> Option<bool> {
+            Some(match self.policy {
+                ConflictPolicy::KeepLast => true,
+                ConflictPolicy::KeepFirst => false,
+                ConflictPolicy::Error => return None,
+                ConflictPolicy::PreferByColumn { values, .. } => {
+                    let column_index = prefer_by_index.expect("set for this policy");
+                    let rank = |row: &Row| {
+                        let value = row.record.get(column_index).unwrap_or_default();
+                        values.iter().position(|v| v.as_bytes() == value)
+                    };
+                    rank(current) >= rank(previous)
+                }
+            })
+        }
+    }
+
+    /// Return a copy of `record` with its fields in the order of the output columns, with missing fields being empty.
+    fn reorder(record: &csv::ByteRecord, union_to_input: &[Option<usize>]) -> csv::ByteRecord {
+        let mut out = csv::ByteRecord::with_capacity(record.as_slice().len(), union_to_input.len());
+        for input_idx in union_to_input {
+            out.push_field(
+                input_idx
+                    .and_then(|idx| record.get(idx))
+                    .unwrap_or_default(),
+            );
+        }
+        out.set_position(record.position().cloned());
+        out
+    }
+
+    /// Return all fields of `record` without surrounding whitespace and trailing empty fields, separated by the ASCII unit separator.
+    fn normalized_row(record: &csv::ByteRecord) -> Vec<u8> {
+        let mut fields: Vec<_> = record.iter().map(<[u8]>::trim_ascii).collect();
+        while fields.last().is_some_and(|field| field.is_empty()) {
+            fields.pop();
+        }
+        fields.join(&0x1f)
+    }
+
+    /// Compare `a` and `b` field by field, treating missing fields like empty ones,
+    /// and ignoring whitespace around fields if `trim` is set, like the key of [`KeyMode::WholeRow`] does.
+    fn same_fields(a: &csv::ByteRecord, b: &csv::ByteRecord, trim: bool) -> bool {
+        fn field(record: &csv::ByteRecord, idx: usize, trim: bool) -> &[u8] {
+            let field = record.get(idx).unwrap_or_default();
+            if trim {
+                field.trim_ascii()
+            } else {
+                field
+            }
+        }
+        (0..a.len().max(b.len())).all(|idx| field(a, idx, trim) == field(b, idx, trim))
+    }
+
+    /// Reads from `R` while keeping track of the offsets of line feeds to tell the line a record starts in,
+    /// as the line of a record's position is one too low if lines end with CRLF.
+    struct LineFeeds<R> {
+        read: R,
+        /// The amount of bytes read so far.
+        offset: u64,
+        /// The offsets of line feeds that weren't passed by a record yet.
+        line_feeds: std::collections::VecDeque<u64>,
+        /// The amount of line feeds passed by records.
+        num_passed: u64,
+    }
+
+    impl<R> LineFeeds<R> {
+        fn new(read: R) -> Self {
+            LineFeeds {
+                read,
+                offset: 0,
+                line_feeds: Default::default(),
+                num_passed: 0,
+            }
+        }
+
+        /// Return the line of the record at `byte`, which must not be smaller than the one of the previous record.
+        fn line(&mut self, byte: u64) -> u64 {
+            while self.line_feeds.front().is_some_and(|offset| *offset < byte) {
+                self.line_feeds.pop_front();
+                self.num_passed += 1;
+            }
+            // With CRLF, a record starts at the line feed that ends the previous line.
+            let is_at_line_feed = self.line_feeds.front() == Some(&byte);
+            self.num_passed + u64::from(is_at_line_feed) + 1
+        }
+    }
+
+    impl<R: std::io::Read> std::io::Read for LineFeeds<R> {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            let num_read = self.read.read(buf)?;
+            for (idx, byte) in buf[..num_read].iter().enumerate() {
+                if *byte == b'\n' {
+                    self.line_feeds.push_back(self.offset + idx as u64);
+                }
+            }
+            self.offset += num_read as u64;
+            Ok(num_read)
+        }
+    }
+
+    /// A row along with the index of the input it was read from.
+    pub(crate) struct Row {
+        pub record: csv::ByteRecord,
+        pub input: usize,
+        /// The amount of inputs the key of this row was seen in.
+        pub seen_in_inputs: usize,
+    }
+
+    impl Row {
+        pub fn line(&self) -> u64 {
+            self.record.position().map(|p| p.line()).unwrap_or_default()
+        }
+
+        fn location(&self) -> Location {
+            Location {
+                input: self.input,
+                line: self.line(),
+            }
+        }
+    }
+
+    fn field_changes(
+        headers: &csv::StringRecord,
+        previous: &csv::ByteRecord,
+        current: &csv::ByteRecord,
+    ) -> Vec<FieldChange> {
+        (0..previous.len().max(current.len()))
+            .filter(|idx| {
+                previous.get(*idx).unwrap_or_default() != current.get(*idx).unwrap_or_default()
+            })
+            .map(|idx| FieldChange {
+                column: headers.get(idx).unwrap_or_default().to_owned(),
+                previous: lossy(previous.get(idx)),
+                current: lossy(current.get(idx)),
+            })
+            .collect()
+    }
+
+    fn lossy(value: Option<&[u8]>) -> String {
+        String::from_utf8_lossy(value.unwrap_or_default()).into_owned()
+    }
+
+    /// A parsed value of the sort column.
+    #[derive(PartialEq, PartialOrd)]
+    enum SortValue<'a> {
+        Text(Cow<'a, [u8]>),
+        Time(time::OffsetDateTime),
+        Number(f64),
+    }
+
+    impl SortValue<'_> {
+        fn into_owned(self) -> SortValue<'static> {
+            match self {
+                SortValue::Text(text) => SortValue::Text(Cow::Owned(text.into_owned())),
+                SortValue::Time(time) => SortValue::Time(time),
+                SortValue::Number(number) => SortValue::Number(number),
+            }
+        }
+    }
+
+    impl Eq for SortValue<'_> {}
+
+    impl Ord for SortValue<'_> {
+        fn cmp(&self, other: &Self) -> Ordering {
+            match (self, other) {
+                (SortValue::Number(a), SortValue::Number(b)) => a.total_cmp(b),
+                _ => self
+                    .partial_cmp(other)
+                    .expect("values of one column have the same type"),
+            }
+        }
+    }
+
+    /// Parse the sort column of `record` according to `sort_type`, or return `None` if it is empty.
+    /// `date_format` is the parsed format description if `sort_type` is [`SortType::Date`].
+    fn sort_value<'a>(
+        record: &'a csv::ByteRecord,
+        sort_column_index: usize,
+        sort_type: &SortType,
+        date_format: &[time::format_description::FormatItem<'_>],
+    ) -> Result<Option<SortValue<'a>>, Error> {
+        let value = record.get(sort_column_index).unwrap_or_default();
+        if value.is_empty() {
+            return Ok(None);
+        }
+        let parse_error = |kind: &'static str| Error::ParseSortValue {
+            line: record.position().map(|p| p.line()).unwrap_or_default(),
+            value: String::from_utf8_lossy(value).into_owned(),
+            kind,
+        };
+        Ok(Some(match sort_type {
+            SortType::Text => SortValue::Text(Cow::Borrowed(value)),
+            SortType::Date { .. } => {
+                let value = std::str::from_utf8(value).map_err(|_| parse_error("date"))?;
+                SortValue::Time(
+                    time::OffsetDateTime::parse(value, date_format)
+                        .or_else(|_| {
+                            time::PrimitiveDateTime::parse(value, date_format)
+                                .map(time::PrimitiveDateTime::assume_utc)
+                        })
+                        .or_else(|_| {
+                            time::Date::parse(value, date_format)
+                                .map(|date| date.midnight().assume_utc())
+                        })
+                        .map_err(|_| parse_error("date"))?,
+                )
+            }
+            SortType::DateTime => {
+                let value = std::str::from_utf8(value).map_err(|_| parse_error("date-time"))?;
+                let time = gix_date::parse(value, None).map_err(|_| parse_error("date-time"))?;
+                SortValue::Time(
+                    time::OffsetDateTime::from_unix_timestamp(time.seconds)
+                        .map_err(|_| parse_error("date-time"))?,
+                )
+            }
+            SortType::Number { decimal_separator } => {
+                let number: String = String::from_utf8_lossy(value)
+                    .chars()
+                    .filter_map(|c| match c {
+                        '0'..='9' | '-' => Some(c),
+                        c if c == *decimal_separator => Some('.'),
+                        _ => None,
+                    })
+                    .collect();
+                SortValue::Number(number.parse().map_err(|_| parse_error("number"))?)
+            }
+        }))
+    }
+
     fn to_string(headers: &csv::StringRecord) -> String {
         let mut buf = Vec::<u8>::new();
         {
@@ -130,14 +911,4 @@ pub(crate) mod function {
         }
         String::from_utf8_lossy(&buf).into_owned()
     }
-
-    /// Return the position of `name_or_index` in `headers` or `None` if it wasn't found.
-    /// If `name_or_index` is a number, it will be used as number and not as name.
-    fn header_idx(name_or_index: &str, headers: &csv::StringRecord) -> Option<usize> {
-        if let Ok(index) = name_or_index.parse() {
-            headers.get(index).map(|_| index)
-        } else {
-            headers.iter().position(|name| name == name_or_index)
-        }
-    }
 }
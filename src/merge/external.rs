@@ -0,0 +1,224 @@
+//! Sort rows that don't fit into memory by spilling sorted runs to temporary files, and merging these runs while reading them back.
+use crate::merge::function::Row;
+use crate::merge::Error;
+use std::fs::File;
+use tempfile::TempPath;
+
+/// The most runs that are merged at once, which bounds the amount of open temporary files.
+const MAX_RUNS_PER_MERGE: usize = 16;
+
+/// Collects rows with their key and spills them to a temporary file, sorted by rank and key, whenever `limit` rows are buffered.
+///
+/// Runs are only open while they are merged. Whenever [`MAX_RUNS_PER_MERGE`] runs of the same generation were spilled,
+/// they are merged into one run of the next generation, so each row is only rewritten a logarithmic amount of times.
+pub(crate) struct Spill<R> {
+    limit: usize,
+    buffer: Vec<(R, Vec<u8>, Row)>,
+    /// The spilled runs along with their generation, oldest first.
+    runs: Vec<(usize, TempPath)>,
+}
+
+impl<R: Ord> Spill<R> {
+    pub fn new(limit: usize) -> Self {
+        Spill {
+            limit: limit.max(1),
+            buffer: Vec::new(),
+            runs: Vec::new(),
+        }
+    }
+
+    pub fn limit(&self) -> usize {
+        self.limit
+    }
+
+    /// Add `row` with `key`, ranked by `rank`.
+    pub fn push(
+        &mut self,
+        key: Vec<u8>,
+        row: Row,
+        rank: impl Fn(&Row) -> Result<R, Error>,
+    ) -> Result<(), Error> {
+        self.buffer.push((rank(&row)?, key, row));
+        if self.buffer.len() >= self.limit {
+            self.spill()?;
+            while let Some(generation) = self.full_generation() {
+                let first = self.runs.len() - MAX_RUNS_PER_MERGE;
+                self.merge_runs(first..self.runs.len(), generation + 1, &rank)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Spill all remaining rows and return all rows ordered by rank and key, with the rows pushed earlier first on ties.
+    /// `rank` must be the function the rows were pushed with.
+    pub fn into_merged<F>(mut self, rank: F) -> Result<Merged<R, F>, Error>
+    where
+        F: Fn(&Row) -> Result<R, Error>,
+    {
+        if !self.buffer.is_empty() {
+            self.spill()?;
+        }
+        // Merge neighbouring runs in passes to keep their order, until all remaining runs can be merged at once.
+        while self.runs.len() > MAX_RUNS_PER_MERGE {
+            let mut first = 0;
+            while first < self.runs.len() {
+                let last = (first + MAX_RUNS_PER_MERGE).min(self.runs.len());
+                self.merge_runs(first..last, 0, &rank)?;
+                first += 1;
+            }
+        }
+        Merged::new(self.runs.into_iter().map(|(_, path)| path).collect(), rank)
+    }
+
+    /// Return the generation of the last [`MAX_RUNS_PER_MERGE`] runs if they all have the same one.
+    fn full_generation(&self) -> Option<usize> {
+        let first = self.runs.len().checked_sub(MAX_RUNS_PER_MERGE)?;
+        let generation = self.runs[first].0;
+        self.runs[first..]
+            .iter()
+            .all(|(other, _)| *other == generation)
+            .then_some(generation)
+    }
+
+    /// Replace the runs in `range` with one run of `generation` that has all of their rows, and remove their files.
+    fn merge_runs(
+        &mut self,
+        range: std::ops::Range<usize>,
+        generation: usize,
+        rank: impl Fn(&Row) -> Result<R, Error>,
+    ) -> Result<(), Error> {
+        let start = range.start;
+        let runs: Vec<_> = self.runs.drain(range).map(|(_, path)| path).collect();
+        let mut merged = Merged::new(runs, rank)?;
+        let path = write_run(std::iter::from_fn(|| merged.next().transpose()))?;
+        self.runs.insert(start, (generation, path));
+        Ok(())
+    }
+
+    fn spill(&mut self) -> Result<(), Error> {
+        // A stable sort keeps rows with the same rank and key in the order they were pushed in.
+        self.buffer
+            .sort_by(|(a_rank, a_key, _), (b_rank, b_key, _)| {
+                (a_rank, a_key).cmp(&(b_rank, b_key))
+            });
+        let path = write_run(self.buffer.drain(..).map(|(_, key, row)| Ok((key, row))))?;
+        self.runs.push((0, path));
+        Ok(())
+    }
+}
+
+/// Write `rows` along with their keys to a new temporary file, and return its path after closing it.
+fn write_run(rows: impl Iterator<Item = Result<(Vec<u8>, Row), Error>>) -> Result<TempPath, Error> {
+    let mut file = tempfile::NamedTempFile::new().map_err(Error::TempFile)?;
+    {
+        let mut out = csv::WriterBuilder::new()
+            .flexible(true)
+            .from_writer(file.as_file_mut());
+        let mut spilled = csv::ByteRecord::new();
+        for row in rows {
+            let (key, row) = row?;
+            spilled.clear();
+            spilled.push_field(&key);
+            spilled.push_field(row.input.to_string().as_bytes());
+            spilled.push_field(row.seen_in_inputs.to_string().as_bytes());
+            spilled.push_field(row.line().to_string().as_bytes());
+            for field in &row.record {
+                spilled.push_field(field);
+            }
+            out.write_byte_record(&spilled)
+                .map_err(Error::WriteSpilledRows)?;
+        }
+        out.flush()
+            .map_err(|err| Error::WriteSpilledRows(err.into()))?;
+    }
+    Ok(file.into_temp_path())
+}
+
+/// Spilled runs, merged into one sequence of rows.
+pub(crate) struct Merged<R, F> {
+    runs: Vec<csv::Reader<File>>,
+    /// The paths of the runs, which are removed when dropped.
+    _paths: Vec<TempPath>,
+    /// The next row of each run, if there is one.
+    heads: Vec<Option<(R, Vec<u8>, Row)>>,
+    rank: F,
+}
+
+impl<R, F> Merged<R, F>
+where
+    R: Ord,
+    F: Fn(&Row) -> Result<R, Error>,
+{
+    fn new(paths: Vec<TempPath>, rank: F) -> Result<Self, Error> {
+        let mut runs = paths
+            .iter()
+            .map(|path| {
+                let file = File::open(path).map_err(|err| Error::ReadSpilledRows(err.into()))?;
+                Ok(csv::ReaderBuilder::new()
+                    .flexible(true)
+                    .has_headers(false)
+                    .from_reader(file))
+            })
+            .collect::<Result<Vec<_>, Error>>()?;
+        let heads = runs
+            .iter_mut()
+            .map(|run| read_row(run, &rank))
+            .collect::<Result<_, _>>()?;
+        Ok(Merged {
+            runs,
+            _paths: paths,
+            heads,
+            rank,
+        })
+    }
+
+    /// Return the next row along with its key, or `None` if all rows were returned.
+    pub fn next(&mut self) -> Result<Option<(Vec<u8>, Row)>, Error> {
+        let mut min = None::<(usize, (&R, &Vec<u8>))>;
+        for (idx, head) in self.heads.iter().enumerate() {
+            if let Some((rank, key, _)) = head {
+                if min.is_none_or(|(_, min)| (rank, key) < min) {
+                    min = Some((idx, (rank, key)));
+                }
+            }
+        }
+        let Some((idx, _)) = min else {
+            return Ok(None);
+        };
+        let next = read_row(&mut self.runs[idx], &self.rank)?;
+        let (_, key, row) = std::mem::replace(&mut self.heads[idx], next).expect("present");
+        Ok(Some((key, row)))
+    }
+}
+
+fn read_row<R>(
+    run: &mut csv::Reader<File>,
+    rank: impl Fn(&Row) -> Result<R, Error>,
+) -> Result<Option<(R, Vec<u8>, Row)>, Error> {
+    let mut spilled = csv::ByteRecord::new();
+    if !run
+        .read_byte_record(&mut spilled)
+        .map_err(Error::ReadSpilledRows)?
+    {
+        return Ok(None);
+    }
+    let number = |idx: usize| -> u64 {
+        std::str::from_utf8(&spilled[idx])
+            .ok()
+            .and_then(|number| number.parse().ok())
+            .expect("we wrote a number")
+    };
+    let mut record = csv::ByteRecord::with_capacity(spilled.as_slice().len(), spilled.len() - 4);
+    for field in spilled.iter().skip(4) {
+        record.push_field(field);
+    }
+    let mut position = csv::Position::new();
+    position.set_line(number(3));
+    record.set_position(Some(position));
+    let row = Row {
+        record,
+        input: number(1) as usize,
+        seen_in_inputs: number(2) as usize,
+    };
+    Ok(Some((rank(&row)?, spilled[0].to_vec(), row)))
+}
@@ -1,11 +1,20 @@
+use crate::merge::{Conflict, ConflictPolicy};
+use crate::Dialect;
+use std::ops::RangeInclusive;
 use std::path::PathBuf;
 
+mod amount;
+mod matching;
+pub mod overrides;
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error(transparent)]
     Csv(#[from] csv::Error),
     #[error(transparent)]
     Merge(#[from] crate::merge::Error),
+    #[error(transparent)]
+    Dialect(#[from] crate::dialect::Error),
     #[error("A {kind} column at index {index} in row at line {line} could not be found")]
     MissingColumn {
         index: usize,
@@ -14,6 +23,13 @@ pub enum Error {
     },
     #[error("Date '{date}' contained invalid UTf-8")]
     InvalidDateEncoding { date: String },
+    #[error("Invalid format description '{format}' for the date and time of stripe rows")]
+    InvalidDateTimeFormat {
+        format: String,
+        source: time::error::InvalidFormatDescription,
+    },
+    #[error("At least one format description is needed to parse the date and time of stripe rows")]
+    MissingDateTimeFormat,
     #[error("Failed to parse time '{date_time}'")]
     ParseTime {
         date_time: String,
@@ -28,6 +44,34 @@ pub enum Error {
     OpenNotesFile(#[from] std::io::Error),
     #[error("Could not decode the matching engine to attach notes with")]
     DecodeNotes(#[from] ron::de::SpannedError),
+    #[error("Failed to open overrides file at '{}' for reading", path.display())]
+    OpenOverridesFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+    #[error("Could not decode the overrides at '{}'", path.display())]
+    DecodeOverrides {
+        path: PathBuf,
+        source: ron::de::SpannedError,
+    },
+    #[error("Override {} in '{}' has the date-time '{date_time}' which could not be parsed", index + 1, path.display())]
+    InvalidOverrideDateTime {
+        path: PathBuf,
+        /// The position of the override in the file, starting at 0.
+        index: usize,
+        date_time: String,
+        source: gix_date::parse::Error,
+    },
+    #[error("Could not read reference rates from '{}'", path.display())]
+    ReadReferenceRates { path: PathBuf, source: csv::Error },
+    #[error("The reference rates at '{}' lack a '{column}' column", path.display())]
+    MissingReferenceRateColumn { path: PathBuf, column: &'static str },
+    #[error("The reference rate '{rate}' of '{currency}' in '{}' isn't a positive number", path.display())]
+    InvalidReferenceRate {
+        path: PathBuf,
+        currency: String,
+        rate: String,
+    },
 }
 
 impl Error {
@@ -44,11 +88,75 @@ impl Error {
 #[derive(Clone, Debug)]
 pub struct Options {
     pub stripe_date_column: String,
-    pub stripe_time_column: String,
+    /// The index or name of the column with the time of a Stripe transaction, which is appended to its date for parsing,
+    /// or `None` if `stripe_date_column` contains both, like `Created (UTC)`.
+    pub stripe_time_column: Option<String>,
+    /// The `time` format descriptions to parse the date and time of Stripe transactions with, tried in order until one fits.
+    ///
+    /// Date-times without an offset are in UTC.
+    pub stripe_date_time_formats: Vec<String>,
     pub stripe_delimiter: char,
     pub github_date_column: String,
+    /// The index or name of the column that identifies a GitHub transaction across exports.
+    pub github_key_column: String,
+    /// The index or name of the GitHub column with the ID to look for in [`stripe_id_column`](Options::stripe_id_column).
+    pub github_id_column: String,
+    /// The index or name of the Stripe column with the ID of the GitHub transaction, like a charge ID or a description containing it.
+    ///
+    /// If set, rows are paired by ID first and only the remaining rows are paired by time,
+    /// with a `Matched By` column telling which it was.
+    pub stripe_id_column: Option<String>,
+    /// The index or name of the Stripe column that identifies a row, for [`StripeRow::Id`](overrides::StripeRow::Id) overrides.
+    pub stripe_row_id_column: String,
+    /// What to do if a GitHub transaction is seen again with different content.
+    pub github_conflict_policy: ConflictPolicy,
     pub github_delimiter: char,
-    pub max_distance_seconds: u64,
+    /// The most seconds a Stripe row may be booked before a GitHub row to be paired with it, to allow for clock skew.
+    pub max_before_seconds: u64,
+    /// The most seconds a Stripe row may be booked after a GitHub row to be paired with it.
+    pub max_after_seconds: u64,
+    /// If set, replace `max_after_seconds` with a window chosen from the lags of the pairs that are unambiguous
+    /// within this many seconds, that is GitHub and Stripe rows which are the only candidate of each other.
+    ///
+    /// The chosen window is twice the 99th percentile of these lags, and `max_after_seconds` is kept if there are none.
+    /// It is reported in [`Outcome::auto_window`].
+    pub auto_window_seconds: Option<u64>,
+    /// How to pair GitHub rows with Stripe rows.
+    pub matching: Matching,
+    /// If `true`, collect the Stripe rows looked at for each GitHub row and why they weren't paired with it.
+    pub explain: bool,
+    /// The index or name of the column with the amount GitHub processed, in USD.
+    pub github_amount_column: String,
+    /// The index or name of the column with the amount received on Stripe, in the local currency.
+    pub stripe_amount_column: String,
+    /// The index or name of the column with the currency of the amount received on Stripe, like `EUR`.
+    pub stripe_currency_column: String,
+    /// The rates of Stripe amount per GitHub amount which are plausible for a pair.
+    ///
+    /// The plausibility of the amounts is part of the `Confidence` column, along with the distance of the pair
+    /// and how close the next best candidate was.
+    /// If there are `reference_rates`, these rates are relative to the reference rate of the Stripe row's currency,
+    /// and `None` uses `0.5..=1.5`. Pairs in currencies without a reference rate aren't judged.
+    /// Otherwise these rates are the same for all currencies, and `None` doesn't judge any amounts.
+    pub plausible_rates: Option<RangeInclusive<f64>>,
+    /// The path to a CSV file with a `Currency` and a `Rate` column, the amount of the currency worth one USD.
+    pub reference_rates: Option<PathBuf>,
+    /// What to do with pairs whose amounts aren't plausible.
+    pub on_implausible_amount: AmountPolicy,
+    /// If set, pair Stripe rows that remain unpaired with a group of unpaired GitHub rows booked at most this many seconds
+    /// before them, whose summed amounts convert to the Stripe amount within `batch_tolerance`, as Stripe may book several
+    /// payments as one.
+    ///
+    /// The rows of each group have the same number in a `Group` column.
+    pub batch_window_seconds: Option<u64>,
+    /// The most GitHub rows in a group if `batch_window_seconds` is set.
+    pub max_batch_size: usize,
+    /// How far the rate implied by the summed amounts of a group may be from the centre of the plausible rates of its
+    /// Stripe row, relative to that centre, like `0.02` for 2%.
+    ///
+    /// With `reference_rates` and the default `plausible_rates`, the centre is the reference rate of the Stripe row's currency.
+    /// Stripe rows without plausible rates aren't paired with any group.
+    pub batch_tolerance: f64,
     /// the possible characters that denote the start of a number that we are supposed to normalize
     pub number_markers: String,
     /// Separator for use with number normalization.
@@ -56,110 +164,506 @@ pub struct Options {
     /// Separator for use with number normalization.
     pub thousands_separator: char,
     pub notes: Option<PathBuf>,
+    /// The path to a `.ron` file with [overrides](overrides::Overrides) which pin GitHub rows to Stripe rows,
+    /// or keep rows from being paired, before any automatic matching.
+    ///
+    /// Pinned pairs have `override` in a `Matched By` column, and overrides that reference nothing
+    /// are reported in [`Outcome::unresolved_overrides`].
+    pub overrides: Option<PathBuf>,
+    /// The CSV flavor to write the output in.
+    pub output: Dialect,
+}
+
+/// Where to write the rows produced by [`merge_accounts()`](crate::merge_accounts()) to.
+pub enum Sink<W> {
+    /// Write all GitHub rows, paired or not, followed by all unmatched Stripe rows into one output,
+    /// with the columns of both and an `Unmatched Reason` column.
+    Combined(W),
+    /// Write each category of rows with its own columns into its own output, or into `rest` if there is none.
+    Split {
+        /// GitHub rows that were paired with a Stripe row, with the columns of both.
+        matched: Option<W>,
+        /// GitHub rows that weren't paired with any Stripe row, with only the GitHub columns and an `Unmatched Reason` column.
+        unmatched_github: Option<W>,
+        /// Stripe rows that weren't paired with any GitHub row, with only the Stripe columns and an `Unmatched Reason` column.
+        unmatched_stripe: Option<W>,
+        /// The rows of all categories without their own output, like [`Sink::Combined`] would write them,
+        /// or `None` to drop them.
+        rest: Option<W>,
+    },
+}
+
+/// Determines what happens to pairs whose amounts imply an implausible rate.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AmountPolicy {
+    /// Pair them anyway, and report them in [`Outcome::implausible_pairs`].
+    #[default]
+    Flag,
+    /// Never pair them, leaving the rows to other candidates.
+    Reject,
+}
+
+/// The window that was chosen from the lags of unambiguous pairs if [`Options::auto_window_seconds`] is set.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AutoWindow {
+    /// The amount of unambiguous pairs whose lags the window was chosen from.
+    pub num_pairs: usize,
+    /// The percentile along with the lag in seconds that many percent of the unambiguous pairs didn't exceed,
+    /// empty if there are no such pairs.
+    pub lag_percentiles: Vec<(u8, i64)>,
+    /// The most seconds a Stripe row was allowed to be booked after a GitHub row to be paired with it.
+    pub max_after_seconds: u64,
+}
+
+/// A GitHub row that was paired with a Stripe row whose amount doesn't fit.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ImplausiblePair {
+    /// The key of the GitHub row, like its transaction ID.
+    pub github_key: String,
+    /// The date-time of the Stripe row it is paired with.
+    pub date_time: time::OffsetDateTime,
+    /// The Stripe amount per GitHub amount.
+    pub rate: f64,
+}
+
+/// The reason for a Stripe row not being paired with a GitHub row.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnmatchedReason {
+    /// No GitHub row was booked within `max_before_seconds` after it or `max_after_seconds` before it.
+    NoGithubRowWithinWindow,
+    /// It was a candidate of at least one GitHub row, but each of these was paired with another Stripe row.
+    CandidateTakenByAnotherRow,
+    /// It was booked within the window of at least one GitHub row, but its amount fits to none of them.
+    ImplausibleAmount,
+    /// An override keeps it from being paired.
+    IntentionallyUnmatched,
+}
+
+impl UnmatchedReason {
+    /// Return a human-readable description of the reason.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnmatchedReason::NoGithubRowWithinWindow => "no GitHub row within window",
+            UnmatchedReason::CandidateTakenByAnotherRow => "candidate taken by another row",
+            UnmatchedReason::ImplausibleAmount => "implausible amount",
+            UnmatchedReason::IntentionallyUnmatched => "intentionally unmatched",
+        }
+    }
+}
+
+/// Determines how GitHub rows are paired with the Stripe rows booked after them.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Matching {
+    /// Each GitHub row, from the earliest to the latest date-time, takes the closest Stripe row within
+    /// `max_before_seconds` before and `max_after_seconds` after it that wasn't taken yet.
+    ///
+    /// This may leave a later GitHub row without its best candidate.
+    #[default]
+    Greedy,
+    /// Pair as many rows as possible such that the sum of all distances is the smallest,
+    /// and report the pairs that differ from [greedy](Matching::Greedy) matching.
+    ///
+    /// GitHub rows that share candidates with too many other rows are paired greedily, see
+    /// [`Outcome::num_greedy_github_rows`].
+    Optimal,
+}
+
+/// A GitHub row that is paired differently than it would be with greedy matching.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChangedPair {
+    /// The key of the GitHub row, like its transaction ID.
+    pub github_key: String,
+    /// The date-time of the Stripe row that greedy matching would pair it with, if any.
+    pub greedy: Option<time::OffsetDateTime>,
+    /// The date-time of the Stripe row it is paired with, if any.
+    pub current: Option<time::OffsetDateTime>,
+}
+
+/// Why a Stripe row wasn't paired with a GitHub row it was looked at for.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Rejection {
+    /// It was booked more than `max_before_seconds` before the GitHub row.
+    NegativeOffset,
+    /// It was booked more than `max_after_seconds` after the GitHub row, or more than the automatically chosen window.
+    BeyondMaxDistance,
+    /// It was already paired with another GitHub row.
+    AlreadyConsumed,
+    /// Another candidate was paired with the GitHub row.
+    AnotherCandidateChosen,
+    /// Its amount doesn't fit to the amount of the GitHub row.
+    ImplausibleAmount,
+}
+
+impl Rejection {
+    /// Return a human-readable description of the rejection.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Rejection::NegativeOffset => "negative offset",
+            Rejection::BeyondMaxDistance => "beyond max distance",
+            Rejection::AlreadyConsumed => "already consumed",
+            Rejection::AnotherCandidateChosen => "another candidate was chosen",
+            Rejection::ImplausibleAmount => "implausible amount",
+        }
+    }
+}
+
+/// A Stripe row that was looked at for a GitHub row.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// The date-time of the Stripe row.
+    pub date_time: time::OffsetDateTime,
+    /// The seconds between the GitHub row and the Stripe row, negative if the Stripe row was booked first.
+    pub offset_seconds: i64,
+    /// Why it wasn't paired with the GitHub row, or `None` if it was.
+    pub rejection: Option<Rejection>,
+}
+
+/// The Stripe rows around a GitHub row and how they were judged.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explanation {
+    /// The key of the GitHub row, like its transaction ID.
+    pub github_key: String,
+    /// The date-time of the GitHub row.
+    pub date_time: time::OffsetDateTime,
+    /// The closest Stripe row booked before its window, all Stripe rows within `max_before_seconds` before it
+    /// and `max_after_seconds` after it, and the first Stripe row beyond that, ordered by date-time.
+    pub candidates: Vec<Candidate>,
+}
+
+/// Write each of `explanations` as JSON object on its own line to `out`.
+pub fn write_explanations(
+    explanations: &[Explanation],
+    mut out: impl std::io::Write,
+) -> std::io::Result<()> {
+    #[derive(serde::Serialize)]
+    struct Line<'a> {
+        github_key: &'a str,
+        date_time: String,
+        candidates: Vec<CandidateLine>,
+    }
+    #[derive(serde::Serialize)]
+    struct CandidateLine {
+        date_time: String,
+        offset_seconds: i64,
+        rejection: Option<&'static str>,
+    }
+    let format = |date_time: &time::OffsetDateTime| {
+        date_time
+            .format(gix_date::time::format::ISO8601)
+            .expect("should always work")
+    };
+    for explanation in explanations {
+        serde_json::to_writer(
+            &mut out,
+            &Line {
+                github_key: &explanation.github_key,
+                date_time: format(&explanation.date_time),
+                candidates: explanation
+                    .candidates
+                    .iter()
+                    .map(|candidate| CandidateLine {
+                        date_time: format(&candidate.date_time),
+                        offset_seconds: candidate.offset_seconds,
+                        rejection: candidate.rejection.map(|rejection| rejection.as_str()),
+                    })
+                    .collect(),
+            },
+        )?;
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Outcome {
+    /// All GitHub transactions that were seen more than once with different content, of which only one was kept.
+    pub github_conflicts: Vec<Conflict>,
+    /// The pairs that differ from greedy matching, if [`Matching::Optimal`] was used.
+    pub changed_pairs: Vec<ChangedPair>,
+    /// The amount of GitHub rows that were paired greedily with [`Matching::Optimal`], as they share candidates with
+    /// so many other rows that pairing them optimally would take too long.
+    pub num_greedy_github_rows: usize,
+    /// The amount of GitHub rows that were paired with a Stripe row.
+    pub num_matched: usize,
+    /// The amount of GitHub rows that weren't paired with any Stripe row.
+    pub num_unmatched_github_rows: usize,
+    /// The amount of Stripe rows that weren't paired with any GitHub row.
+    pub num_unmatched_stripe_rows: usize,
+    /// The amount of GitHub rows that had more than one Stripe row to choose from, which makes their pairs less certain.
+    pub num_ambiguous_github_rows: usize,
+    /// The explanation of each GitHub row's pairing, if [`Options::explain`] is `true`.
+    pub explanations: Vec<Explanation>,
+    /// The pairs whose amounts imply a rate outside of [`Options::plausible_rates`], if [`AmountPolicy::Flag`] is used.
+    pub implausible_pairs: Vec<ImplausiblePair>,
+    /// The window that pairs were formed with, if [`Options::auto_window_seconds`] is set.
+    pub auto_window: Option<AutoWindow>,
+    /// The overrides of [`Options::overrides`] that weren't applied as they reference nothing.
+    pub unresolved_overrides: Vec<overrides::Unresolved>,
 }
 
 impl Default for Options {
     fn default() -> Self {
         Options {
             stripe_date_column: "Date".into(),
-            stripe_time_column: "Time".into(),
+            stripe_time_column: Some("Time".into()),
+            stripe_date_time_formats: vec![
+                "[month repr:long] [day padding:none], [year][hour]:[minute]:[second] UTC".into(),
+            ],
             stripe_delimiter: ',',
             github_date_column: "Transaction Date".into(),
+            github_key_column: "Transaction ID".into(),
+            github_id_column: "Transaction ID".into(),
+            stripe_id_column: None,
+            stripe_row_id_column: "id".into(),
+            github_conflict_policy: ConflictPolicy::default(),
             github_delimiter: ',',
-            max_distance_seconds: 10,
+            max_before_seconds: 0,
+            max_after_seconds: 10,
+            auto_window_seconds: None,
+            matching: Matching::default(),
+            explain: false,
+            github_amount_column: "Processed Amount".into(),
+            stripe_amount_column: "Net".into(),
+            stripe_currency_column: "Currency".into(),
+            plausible_rates: None,
+            reference_rates: None,
+            on_implausible_amount: AmountPolicy::default(),
+            batch_window_seconds: None,
+            max_batch_size: 4,
+            batch_tolerance: 0.02,
             number_markers: "€$".into(),
             thousands_separator: '.',
             decimal_separator: ',',
             notes: None,
+            overrides: None,
+            output: Dialect::default(),
         }
     }
 }
 
 pub(crate) mod function {
-    use crate::merge_accounts::{Error, Options};
-    use crate::{merge, normalize_number, sle};
+    use crate::merge::Input;
+    use crate::merge_accounts::amount::Plausibility;
+    use crate::merge_accounts::{
+        amount, matching, overrides, AmountPolicy, AutoWindow, Candidate, ChangedPair, Error,
+        Explanation, ImplausiblePair, Matching, Options, Outcome, Rejection, Sink, UnmatchedReason,
+    };
+    use crate::Dialect;
+    use crate::{format_number, merge, normalize_number, sle};
     use std::borrow::Cow;
 
     pub fn merge_accounts(
-        github_data: impl IntoIterator<Item = impl std::io::Read>,
-        stripe_data: impl IntoIterator<Item = impl std::io::Read>,
-        out: impl std::io::Write,
+        github_data: impl IntoIterator<Item = Input<impl std::io::Read>>,
+        stripe_data: impl IntoIterator<Item = Input<impl std::io::Read>>,
+        out: Sink<impl std::io::Write>,
         Options {
             stripe_date_column,
             stripe_time_column,
+            stripe_date_time_formats,
             stripe_delimiter,
             github_date_column,
+            github_key_column,
+            github_id_column,
+            stripe_id_column,
+            stripe_row_id_column,
+            github_conflict_policy,
             github_delimiter,
-            max_distance_seconds,
+            max_before_seconds,
+            max_after_seconds,
+            auto_window_seconds,
+            matching,
+            explain,
+            github_amount_column,
+            stripe_amount_column,
+            stripe_currency_column,
+            plausible_rates,
+            reference_rates,
+            on_implausible_amount,
+            batch_window_seconds,
+            max_batch_size,
+            batch_tolerance,
             number_markers,
             thousands_separator,
             decimal_separator,
             notes,
+            overrides,
+            output,
         }: Options,
-    ) -> Result<(), Error> {
+    ) -> Result<Outcome, Error> {
         let mut github_csv = Vec::<u8>::new();
         let merge::Outcome {
             sort_column_index: github_date_index,
+            key_column_indices: github_key_indices,
             delimiter,
+            conflicts: github_conflicts,
             ..
         } = merge(
             github_data,
-            &[&github_date_column],
+            &[&github_key_column],
             &mut github_csv,
             merge::Options {
                 sort_column: github_date_column.clone(),
                 delimiter: github_delimiter,
+                conflict_policy: github_conflict_policy,
+                report_conflicts: true,
+                ..Default::default()
             },
         )?;
 
+        let github_key_index = github_key_indices[0];
         let mut github_csv = csv::ReaderBuilder::new()
             .delimiter(delimiter)
             .has_headers(true)
             .from_reader(github_csv.as_slice());
+        let github_amount_index = merge::header_idx(&github_amount_column, github_csv.headers()?);
         let notes = notes
             .map(|path| -> Result<sle::Engine, Error> {
                 Ok(ron::de::from_reader(std::fs::File::open(path)?)?)
             })
             .transpose()?;
+        let overrides = overrides
+            .map(
+                |path| -> Result<(std::path::PathBuf, overrides::Overrides), Error> {
+                    let file =
+                        std::fs::File::open(&path).map_err(|source| Error::OpenOverridesFile {
+                            path: path.clone(),
+                            source,
+                        })?;
+                    match ron::de::from_reader(file) {
+                        Ok(overrides) => Ok((path, overrides)),
+                        Err(source) => Err(Error::DecodeOverrides { path, source }),
+                    }
+                },
+            )
+            .transpose()?;
+        let reference_rates = reference_rates
+            .map(|path| amount::reference_rates(&path))
+            .transpose()?;
 
         let mut stripe_csv = Vec::<u8>::new();
-        let merge::Outcome {
-            delimiter,
-            key_column_indices,
-            ..
-        } = merge(
+        let merge::Outcome { delimiter, .. } = merge(
             stripe_data,
-            &[&stripe_date_column, &stripe_time_column],
+            &[],
             &mut stripe_csv,
             merge::Options {
                 sort_column: stripe_date_column.clone(),
                 delimiter: stripe_delimiter,
+                // Distinct transactions may happen in the same second, so only exact duplicates are the same.
+                key_mode: merge::KeyMode::WholeRow,
+                ..Default::default()
             },
         )?;
         let mut stripe_csv = csv::ReaderBuilder::new()
             .delimiter(delimiter)
             .has_headers(true)
             .from_reader(stripe_csv.as_slice());
-        let stripe_column_count = stripe_csv.headers()?.len();
+        let stripe_headers = stripe_csv.headers()?.clone();
+        let stripe_column_count = stripe_headers.len();
+        let stripe_column_idx = |name: &str, kind: &'static str| -> Result<usize, Error> {
+            Ok(merge::header_idx(name, &stripe_headers).ok_or_else(|| {
+                merge::Error::MissingColumn {
+                    name: name.into(),
+                    kind,
+                }
+            })?)
+        };
+        let stripe_date_index = stripe_column_idx(&stripe_date_column, "date")?;
+        let stripe_time_index = stripe_time_column
+            .map(|column| stripe_column_idx(&column, "time"))
+            .transpose()?;
+        if stripe_date_time_formats.is_empty() {
+            return Err(Error::MissingDateTimeFormat);
+        }
+        let stripe_date_time_formats = stripe_date_time_formats
+            .iter()
+            .map(|format| {
+                time::format_description::parse(format).map_err(|err| {
+                    Error::InvalidDateTimeFormat {
+                        format: format.clone(),
+                        source: err,
+                    }
+                })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        let id_indices = stripe_id_column
+            .map(|stripe_id_column| -> Result<_, Error> {
+                let github_id_index = merge::header_idx(&github_id_column, github_csv.headers()?)
+                    .ok_or_else(|| merge::Error::MissingColumn {
+                    name: github_id_column.clone(),
+                    kind: "id",
+                })?;
+                Ok((github_id_index, stripe_column_idx(&stripe_id_column, "id")?))
+            })
+            .transpose()?;
+        let stripe_amount_index = merge::header_idx(&stripe_amount_column, &stripe_headers);
+        let stripe_currency_index = merge::header_idx(&stripe_currency_column, &stripe_headers);
+        let stripe_row_id_index = merge::header_idx(&stripe_row_id_column, &stripe_headers);
+        let has_matched_by = id_indices.is_some() || overrides.is_some();
 
-        let mut out = csv::WriterBuilder::new().delimiter(b',').from_writer(out);
-        {
-            let mut headers = github_csv.headers()?.clone();
-            headers.push_field("Received Date");
-            headers.push_field("Distance [s]");
-            for field in stripe_csv.headers()? {
-                headers.push_field(field);
+        let stripe_lut = into_stripe_lut(
+            &mut stripe_csv,
+            stripe_date_index,
+            stripe_time_index,
+            &stripe_date_time_formats,
+        )?;
+
+        let github_headers = github_csv.headers()?.clone();
+        let github_column_count = github_headers.len();
+        let mut headers = github_headers.clone();
+        headers.push_field("Received Date");
+        headers.push_field("Distance [s]");
+        if has_matched_by {
+            headers.push_field("Matched By");
+        }
+        if batch_window_seconds.is_some() {
+            headers.push_field("Group");
+        }
+        for field in &stripe_headers {
+            headers.push_field(field);
+        }
+        headers.push_field("Confidence");
+        for field in AMOUNT_COLUMNS {
+            headers.push_field(field);
+        }
+        if notes.is_some() {
+            headers.push_field("Note");
+        }
+        let (mut combined, mut matched, mut unmatched_github, mut unmatched_stripe) =
+            (None, None, None, None);
+        match out {
+            Sink::Combined(out) => {
+                headers.push_field("Unmatched Reason");
+                combined = Some(writer(&output, out, &headers)?);
             }
-            if notes.is_some() {
-                headers.push_field("Note");
+            Sink::Split {
+                matched: matched_out,
+                unmatched_github: unmatched_github_out,
+                unmatched_stripe: unmatched_stripe_out,
+                rest,
+            } => {
+                if let Some(out) = rest {
+                    let mut headers = headers.clone();
+                    headers.push_field("Unmatched Reason");
+                    combined = Some(writer(&output, out, &headers)?);
+                }
+                if let Some(out) = matched_out {
+                    matched = Some(writer(&output, out, &headers)?);
+                }
+                if let Some(out) = unmatched_github_out {
+                    let mut headers = github_headers.clone();
+                    if notes.is_some() {
+                        headers.push_field("Note");
+                    }
+                    headers.push_field("Unmatched Reason");
+                    unmatched_github = Some(writer(&output, out, &headers)?);
+                }
+                if let Some(out) = unmatched_stripe_out {
+                    let mut headers = stripe_headers.clone();
+                    headers.push_field("Unmatched Reason");
+                    unmatched_stripe = Some(writer(&output, out, &headers)?);
+                }
             }
-            out.write_record(&headers)?;
         }
-        let mut stripe_lut = into_stripe_lut(
-            &mut stripe_csv,
-            key_column_indices[0],
-            key_column_indices[1],
-        )?;
         let starts_with_currency = {
             let mut markers = Vec::<std::ops::Range<usize>>::new();
             for (idx, _) in number_markers.char_indices().skip(1) {
@@ -168,7 +672,7 @@ pub(crate) mod function {
             }
             if !number_markers.is_empty() {
                 let start = markers.last().map(|r| r.end).unwrap_or_default();
-                markers.push(start..number_markers.as_bytes().len());
+                markers.push(start..number_markers.len());
             }
             move |value: &[u8]| -> bool {
                 for marker_range in &markers {
@@ -180,8 +684,8 @@ pub(crate) mod function {
             }
         };
 
+        let mut github_rows = Vec::new();
         let mut record = csv::ByteRecord::new();
-        let mut rewrite_record = csv::ByteRecord::new();
         while github_csv.read_byte_record(&mut record)? {
             let date_time = record.get(github_date_index).ok_or_else(|| {
                 Error::from_position(github_date_index, record.position(), &github_date_column)
@@ -191,37 +695,293 @@ pub(crate) mod function {
                     date: String::from_utf8_lossy(date_time).into_owned(),
                 })?;
             let date_time =
-                gix_date::parse(date_time, None).map_err(|err| Error::ParseGitTime {
+                parse_github_date_time(date_time).map_err(|source| Error::ParseGitTime {
                     date_time: date_time.to_string(),
-                    source: err,
+                    source,
                 })?;
-            let date_time = time::OffsetDateTime::from_unix_timestamp(date_time.seconds)
-                .expect("this should always work for reasonable times")
-                .to_offset(
-                    time::UtcOffset::from_whole_seconds(date_time.offset)
-                        .expect("reasonable offset"),
-                );
-            let stripe_row = match stripe_lut.binary_search_by(|row| row.date_time.cmp(&date_time))
-            {
-                Ok(idx) => Some((idx, 0)),
-                Err(idx) => [Some(idx), idx.checked_sub(1), Some(idx + 1)]
-                    .into_iter()
-                    .flatten()
-                    .filter_map(|idx| stripe_lut.get(idx).map(|row| (idx, row)))
-                    .map(|(idx, row)| (idx, offset_of(row, &date_time)))
-                    .filter(|t| t.1 .1.is_positive())
-                    // stripe transactions can only happen after the corresponding github transaction
-                    .min_by_key(|t| t.1 .1.whole_seconds().abs())
-                    .and_then(|t| {
-                        let distance = t.1 .1.whole_seconds().unsigned_abs();
-                        (distance <= max_distance_seconds).then_some((t.0, distance))
+            github_rows.push(GithubRow {
+                date_time,
+                row: record.clone(),
+            });
+        }
+
+        let github_times: Vec<_> = github_rows.iter().map(|row| row.date_time).collect();
+        let stripe_times: Vec<_> = stripe_lut.iter().map(|row| row.date_time).collect();
+        let github_amounts: Vec<_> = github_rows
+            .iter()
+            .map(|github_row| {
+                github_amount_index
+                    .and_then(|idx| github_row.row.get(idx))
+                    .and_then(crate::parse_number)
+            })
+            .collect();
+        let stripe_amounts: Vec<_> = stripe_lut
+            .iter()
+            .map(|stripe_row| {
+                let field = |idx: Option<usize>| idx.and_then(|idx| stripe_row.row.get(idx));
+                let currency = field(stripe_currency_index);
+                StripeAmount {
+                    net: field(stripe_amount_index).and_then(crate::parse_number),
+                    band: amount::band(
+                        plausible_rates.as_ref(),
+                        currency,
+                        reference_rates.as_ref(),
+                    ),
+                    reference_rate: reference_rates
+                        .as_ref()
+                        .and_then(|rates| amount::reference_rate(currency, rates)),
+                }
+            })
+            .collect();
+        let plausibility = |github_idx: usize, stripe_idx: usize| {
+            let StripeAmount { net, band, .. } = &stripe_amounts[stripe_idx];
+            amount::plausibility(github_amounts[github_idx], *net, band.as_ref())
+        };
+        let is_compatible = |github_idx: usize, stripe_idx: usize| {
+            on_implausible_amount == AmountPolicy::Flag
+                || plausibility(github_idx, stripe_idx) != Plausibility::Implausible
+        };
+
+        let github_keys: Vec<_> = github_rows
+            .iter()
+            .map(|row| row.row.get(github_key_index).unwrap_or_default())
+            .collect();
+        let stripe_row_ids: Option<Vec<_>> = stripe_row_id_index.map(|idx| {
+            stripe_lut
+                .iter()
+                .map(|row| row.row.get(idx).unwrap_or_default())
+                .collect()
+        });
+        let stripe_nets: Vec<_> = stripe_amounts.iter().map(|amount| amount.net).collect();
+        let overrides::Resolved {
+            pinned,
+            is_unmatched_github,
+            is_unmatched_stripe,
+            unresolved: unresolved_overrides,
+        } = overrides::resolve(
+            overrides
+                .as_ref()
+                .map(|(path, overrides)| (path.as_path(), overrides.overrides.as_slice())),
+            &github_keys,
+            stripe_row_ids.as_deref(),
+            &stripe_times,
+            &stripe_nets,
+        )?;
+        // Rows that overrides decided about take no part in automatic matching.
+        let mut is_decided_github: Vec<_> = pinned
+            .iter()
+            .zip(&is_unmatched_github)
+            .map(|(pinned, is_unmatched)| pinned.is_some() || *is_unmatched)
+            .collect();
+        let mut is_decided_stripe = is_unmatched_stripe.clone();
+        for stripe_idx in pinned.iter().flatten() {
+            is_decided_stripe[*stripe_idx] = true;
+        }
+
+        let id_pairs = match id_indices {
+            Some((github_id_index, stripe_id_index)) => {
+                // Empty IDs never match.
+                let github_ids: Vec<_> = github_rows
+                    .iter()
+                    .zip(&is_decided_github)
+                    .map(|(row, is_decided)| match is_decided {
+                        true => &[],
+                        false => row.row.get(github_id_index).unwrap_or_default(),
+                    })
+                    .collect();
+                let stripe_ids: Vec<_> = stripe_lut
+                    .iter()
+                    .zip(&is_decided_stripe)
+                    .map(|(row, is_decided)| match is_decided {
+                        true => &[],
+                        false => row.row.get(stripe_id_index).unwrap_or_default(),
+                    })
+                    .collect();
+                matching::by_id(&github_ids, &stripe_ids)
+            }
+            None => vec![None; github_rows.len()],
+        };
+        for (github_idx, stripe_idx) in id_pairs.iter().enumerate() {
+            if let Some(stripe_idx) = stripe_idx {
+                is_decided_github[github_idx] = true;
+                is_decided_stripe[*stripe_idx] = true;
+            }
+        }
+        let is_available = |github_idx: usize, stripe_idx: usize| {
+            !is_decided_github[github_idx]
+                && !is_decided_stripe[stripe_idx]
+                && is_compatible(github_idx, stripe_idx)
+        };
+
+        let mut window = matching::Window {
+            before_seconds: max_before_seconds,
+            after_seconds: max_after_seconds,
+        };
+        let auto_window = auto_window_seconds.map(|limit| {
+            let mut lags: Vec<_> = matching::unambiguous(
+                &github_times,
+                &stripe_times,
+                matching::Window {
+                    after_seconds: limit,
+                    ..window
+                },
+                is_available,
+            )
+            .into_iter()
+            .map(|(github_idx, stripe_idx)| {
+                (stripe_times[stripe_idx] - github_times[github_idx]).whole_seconds()
+            })
+            .collect();
+            lags.sort_unstable();
+            let lag_percentiles: Vec<_> = matching::LAG_PERCENTILES
+                .into_iter()
+                .filter_map(|percentile| {
+                    matching::percentile(&lags, percentile).map(|lag| (percentile, lag))
+                })
+                .collect();
+            AutoWindow {
+                num_pairs: lags.len(),
+                max_after_seconds: matching::percentile(&lags, 99)
+                    .map_or(max_after_seconds, |lag| {
+                        (lag.max(0).unsigned_abs() * 2).clamp(1, limit.max(1))
                     }),
+                lag_percentiles,
+            }
+        });
+        if let Some(auto_window) = &auto_window {
+            window.after_seconds = auto_window.max_after_seconds;
+        }
+        let greedy = matching::greedy(&github_times, &stripe_times, window, is_available);
+        let (mut changed_pairs, mut num_greedy_github_rows) = (Vec::new(), 0);
+        let pairs = match matching {
+            Matching::Greedy => greedy,
+            Matching::Optimal => {
+                let pairs;
+                (pairs, num_greedy_github_rows) =
+                    matching::optimal(&github_times, &stripe_times, window, is_available, &greedy);
+                for ((github_row, greedy), current) in github_rows.iter().zip(&greedy).zip(&pairs) {
+                    if greedy != current {
+                        changed_pairs.push(ChangedPair {
+                            github_key: github_row.key(github_key_index),
+                            greedy: greedy.map(|idx| stripe_times[idx]),
+                            current: current.map(|idx| stripe_times[idx]),
+                        });
+                    }
+                }
+                pairs
             }
-            .map(|(idx, distance)| (&stripe_lut[idx], idx, distance));
+        };
+        let mut pairs: Vec<_> = pinned
+            .iter()
+            .zip(&id_pairs)
+            .zip(pairs)
+            .map(|((pinned, by_id), by_time)| pinned.or(*by_id).or(by_time))
+            .collect();
 
-            rewrite_record.clear();
-            for field_value in &record {
-                rewrite_record.push_field(
+        let mut batch_of = vec![None; github_rows.len()];
+        if let Some(batch_window_seconds) = batch_window_seconds {
+            let fit = |github_indices: &[usize], stripe_idx: usize| {
+                if is_unmatched_stripe[stripe_idx]
+                    || github_indices.iter().any(|idx| is_unmatched_github[*idx])
+                {
+                    return None;
+                }
+                let gross = github_indices.iter().map(|idx| github_amounts[*idx]).sum();
+                let StripeAmount { net, band, .. } = &stripe_amounts[stripe_idx];
+                let (rate, band) = (amount::rate(gross, *net)?, band.as_ref()?);
+                let center = (band.start() + band.end()) / 2.0;
+                let deviation = (rate - center).abs();
+                (band.contains(&rate) && deviation <= batch_tolerance * center).then_some(deviation)
+            };
+            let batches = matching::batches(
+                &github_times,
+                &stripe_times,
+                batch_window_seconds,
+                max_batch_size,
+                &pairs,
+                fit,
+            );
+            for (batch_idx, (group, stripe_idx)) in batches.into_iter().enumerate() {
+                let gross = group
+                    .iter()
+                    .map(|idx| github_amounts[*idx])
+                    .sum::<Option<f64>>()
+                    .expect("groups only form with known amounts");
+                for github_idx in group {
+                    pairs[github_idx] = Some(stripe_idx);
+                    batch_of[github_idx] = Some(Batch {
+                        id: batch_idx + 1,
+                        gross,
+                    });
+                }
+            }
+        }
+
+        let num_ambiguous_github_rows = github_times
+            .iter()
+            .enumerate()
+            .filter(|(github_idx, github)| {
+                matching::candidates(github, &stripe_times, window)
+                    .filter(|(stripe_idx, _)| is_available(*github_idx, *stripe_idx))
+                    .nth(1)
+                    .is_some()
+            })
+            .count();
+
+        let mut explanations = Vec::new();
+        if explain {
+            let mut paired_with = vec![None; stripe_times.len()];
+            for (github_idx, stripe_idx) in pairs.iter().enumerate() {
+                if let Some(stripe_idx) = stripe_idx {
+                    paired_with[*stripe_idx] = Some(github_idx);
+                }
+            }
+            for (github_idx, github_row) in github_rows.iter().enumerate() {
+                let mut stripe_indices: Vec<_> =
+                    matching::neighbourhood(&github_row.date_time, &stripe_times, window).collect();
+                if let Some(stripe_idx) = pairs[github_idx] {
+                    if !stripe_indices.iter().any(|(idx, _)| *idx == stripe_idx) {
+                        let offset_seconds =
+                            (stripe_times[stripe_idx] - github_row.date_time).whole_seconds();
+                        stripe_indices.push((stripe_idx, offset_seconds));
+                        stripe_indices.sort_by_key(|(idx, _)| *idx);
+                    }
+                }
+                let candidates = stripe_indices
+                    .into_iter()
+                    .map(|(stripe_idx, offset_seconds)| Candidate {
+                        date_time: stripe_times[stripe_idx],
+                        offset_seconds,
+                        rejection: if pairs[github_idx] == Some(stripe_idx) {
+                            None
+                        } else if offset_seconds.unsigned_abs() > window.bound(offset_seconds) {
+                            // The window may have been chosen automatically, and differ from the one of the options.
+                            Some(if offset_seconds < 0 {
+                                Rejection::NegativeOffset
+                            } else {
+                                Rejection::BeyondMaxDistance
+                            })
+                        } else if !is_compatible(github_idx, stripe_idx) {
+                            Some(Rejection::ImplausibleAmount)
+                        } else {
+                            match paired_with[stripe_idx] {
+                                Some(_) => Some(Rejection::AlreadyConsumed),
+                                None => Some(Rejection::AnotherCandidateChosen),
+                            }
+                        },
+                    })
+                    .collect();
+                explanations.push(Explanation {
+                    github_key: github_row.key(github_key_index),
+                    date_time: github_row.date_time,
+                    candidates,
+                });
+            }
+        }
+
+        let push_normalized = |out: &mut csv::ByteRecord, record: &csv::ByteRecord| {
+            for field_value in record {
+                out.push_field(
                     if starts_with_currency(field_value) {
                         Cow::Owned(normalize_number(
                             field_value,
@@ -234,60 +994,352 @@ pub(crate) mod function {
                     .as_ref(),
                 );
             }
+        };
+        let push_received_date = |out: &mut csv::ByteRecord, stripe_row: &StripeRow| {
+            out.push_field(
+                stripe_row
+                    .date_time
+                    .format(gix_date::time::format::ISO8601)
+                    .expect("should always work")
+                    .as_bytes(),
+            );
+        };
+        let push_note = |out: &mut csv::ByteRecord| {
+            if let Some(engine) = &notes {
+                let note = engine
+                    .matching_rule(out)
+                    .map(|rule| rule.value.clone())
+                    .unwrap_or_default();
+                out.push_field(note.as_bytes());
+            }
+        };
+
+        let (mut num_matched, mut num_unmatched_github_rows) = (0, 0);
+        let mut implausible_pairs = Vec::new();
+        let mut is_paired = vec![false; stripe_lut.len()];
+        let mut rewrite_record = csv::ByteRecord::new();
+        for (github_idx, (github_row, stripe_idx)) in
+            github_rows.iter().zip(pairs.iter().copied()).enumerate()
+        {
+            rewrite_record.clear();
+            push_normalized(&mut rewrite_record, &github_row.row);
 
-            match stripe_row {
-                Some((strip_record, idx, distance)) => {
-                    rewrite_record.push_field(
-                        strip_record
-                            .date_time
-                            .format(gix_date::time::format::ISO8601)
-                            .expect("should always work")
-                            .as_bytes(),
-                    );
-                    rewrite_record.push_field(distance.to_string().as_bytes());
-                    for field_value in &strip_record.row {
+            match stripe_idx {
+                Some(idx) => {
+                    let strip_record = &stripe_lut[idx];
+                    is_paired[idx] = true;
+                    let is_matched_by_id = id_pairs[github_idx].is_some();
+                    let is_pinned = pinned[github_idx].is_some();
+                    let batch = batch_of[github_idx];
+                    let offset_seconds = if is_matched_by_id {
+                        0
+                    } else {
+                        (strip_record.date_time - github_row.date_time).whole_seconds()
+                    };
+                    let distance = offset_seconds.unsigned_abs();
+                    push_received_date(&mut rewrite_record, strip_record);
+                    rewrite_record.push_field(offset_seconds.to_string().as_bytes());
+                    if has_matched_by {
+                        rewrite_record.push_field(match (is_pinned, is_matched_by_id, batch) {
+                            (true, _, _) => b"override",
+                            (false, true, _) => b"id",
+                            (false, false, Some(_)) => b"batch",
+                            (false, false, None) => b"time",
+                        });
+                    }
+                    if batch_window_seconds.is_some() {
                         rewrite_record.push_field(
-                            if starts_with_currency(field_value) {
-                                Cow::Owned(normalize_number(
-                                    field_value,
+                            batch
+                                .map(|batch| batch.id.to_string())
+                                .unwrap_or_default()
+                                .as_bytes(),
+                        );
+                    }
+                    push_normalized(&mut rewrite_record, &strip_record.row);
+                    let runner_up_distance =
+                        matching::candidates(&github_row.date_time, &stripe_times, window)
+                            .filter(|(candidate_idx, _)| {
+                                *candidate_idx != idx && is_available(github_idx, *candidate_idx)
+                            })
+                            .map(|(_, offset)| offset.unsigned_abs())
+                            .min();
+                    let plausibility = match batch {
+                        Some(_) => Plausibility::Plausible,
+                        None => plausibility(github_idx, idx),
+                    };
+                    if plausibility == Plausibility::Implausible && !is_pinned {
+                        implausible_pairs.push(ImplausiblePair {
+                            github_key: github_row.key(github_key_index),
+                            date_time: strip_record.date_time,
+                            rate: amount::rate(github_amounts[github_idx], stripe_amounts[idx].net)
+                                .expect("implausible amounts are known"),
+                        });
+                    }
+                    let confidence = match (
+                        is_matched_by_id || is_pinned,
+                        batch_window_seconds.zip(batch),
+                    ) {
+                        (true, _) => 1.0,
+                        (false, Some((batch_window_seconds, _))) => {
+                            confidence(distance, batch_window_seconds, plausibility, None)
+                        }
+                        (false, None) => confidence(
+                            distance,
+                            window.bound(offset_seconds),
+                            plausibility,
+                            runner_up_distance,
+                        ),
+                    };
+                    let format = |number: Option<f64>, decimals: usize| {
+                        number
+                            .map(|number| {
+                                format_number(
+                                    number,
+                                    decimals,
                                     thousands_separator,
                                     decimal_separator,
-                                ))
-                            } else {
-                                Cow::Borrowed(field_value)
-                            }
-                            .as_ref(),
-                        );
+                                )
+                            })
+                            .unwrap_or_default()
+                    };
+                    let (
+                        gross,
+                        StripeAmount {
+                            net,
+                            reference_rate,
+                            ..
+                        },
+                    ) = (github_amounts[github_idx], &stripe_amounts[idx]);
+                    // The net amount of a batch is attributed to its rows by their share of its gross amount.
+                    let (net, rate) = match batch {
+                        Some(batch) => (
+                            net.zip(gross).map(|(net, gross)| net * gross / batch.gross),
+                            amount::rate(Some(batch.gross), *net),
+                        ),
+                        None => (*net, amount::rate(gross, *net)),
+                    };
+                    let fee = gross
+                        .zip(net)
+                        .zip(*reference_rate)
+                        .map(|((gross, net), reference_rate)| gross * reference_rate - net);
+                    for (number, decimals) in [
+                        (Some(confidence), 2),
+                        (gross, 2),
+                        (net, 2),
+                        (rate, 4),
+                        (fee, 2),
+                    ] {
+                        rewrite_record.push_field(format(number, decimals).as_bytes());
                     }
-                    stripe_lut.remove(idx);
                 }
                 None => {
                     rewrite_record.push_field(&[]); /* combined date-time */
                     rewrite_record.push_field(&[]); /* distance */
+                    if has_matched_by {
+                        rewrite_record.push_field(&[]); /* matched by */
+                    }
+                    if batch_window_seconds.is_some() {
+                        rewrite_record.push_field(&[]); /* group */
+                    }
                     for _ in 0..stripe_column_count {
                         rewrite_record.push_field(&[]);
                     }
+                    rewrite_record.push_field(&[]); /* confidence */
+                    for _ in AMOUNT_COLUMNS {
+                        rewrite_record.push_field(&[]);
+                    }
                 }
             }
-            if let Some(engine) = &notes {
-                rewrite_record.push_field(
-                    engine
-                        .matching_rule(&rewrite_record)
-                        .map(|rule| rule.value.as_bytes())
-                        .unwrap_or_default(),
-                );
+            push_note(&mut rewrite_record);
+            if stripe_idx.is_some() {
+                num_matched += 1;
+            } else {
+                num_unmatched_github_rows += 1;
+            }
+            let reason: &[u8] = if is_unmatched_github[github_idx] {
+                UnmatchedReason::IntentionallyUnmatched.as_str().as_bytes()
+            } else {
+                &[]
+            };
+            match (stripe_idx, &mut matched, &mut unmatched_github) {
+                (Some(_), Some(out), _) => out.write_byte_record(&rewrite_record)?,
+                (None, _, Some(out)) => {
+                    let note = notes
+                        .as_ref()
+                        .and_then(|_| rewrite_record.iter().next_back());
+                    out.write_record(
+                        rewrite_record
+                            .iter()
+                            .take(github_column_count)
+                            .chain(note)
+                            .chain(Some(reason)),
+                    )?;
+                }
+                _ => {
+                    if let Some(out) = &mut combined {
+                        rewrite_record.push_field(reason);
+                        out.write_byte_record(&rewrite_record)?;
+                    }
+                }
+            }
+        }
+
+        // GitHub rows that are intentionally unmatched are no candidates for any Stripe row.
+        let (considered, considered_times): (Vec<_>, Vec<_>) = github_times
+            .iter()
+            .enumerate()
+            .filter(|(github_idx, _)| !is_unmatched_github[*github_idx])
+            .unzip();
+        let is_candidate = matching::is_candidate(
+            &considered_times,
+            &stripe_times,
+            window,
+            |idx, stripe_idx| is_compatible(considered[idx], stripe_idx),
+        );
+        let unmatched = stripe_lut
+            .iter()
+            .zip(is_paired.iter().zip(is_candidate))
+            .zip(&is_unmatched_stripe)
+            .filter(|((_, (is_paired, _)), _)| !**is_paired)
+            .map(|((stripe_row, (_, is_candidate)), is_unmatched)| {
+                let reason = match (is_unmatched, is_candidate) {
+                    (true, _) => UnmatchedReason::IntentionallyUnmatched,
+                    (false, (true, true)) => UnmatchedReason::CandidateTakenByAnotherRow,
+                    (false, (true, false)) => UnmatchedReason::ImplausibleAmount,
+                    (false, (false, _)) => UnmatchedReason::NoGithubRowWithinWindow,
+                };
+                (stripe_row, reason)
+            });
+        let mut num_unmatched_stripe_rows = 0;
+        for (stripe_row, reason) in unmatched {
+            num_unmatched_stripe_rows += 1;
+            rewrite_record.clear();
+            if let Some(out) = &mut unmatched_stripe {
+                push_normalized(&mut rewrite_record, &stripe_row.row);
+                rewrite_record.push_field(reason.as_str().as_bytes());
+                out.write_byte_record(&rewrite_record)?;
+            } else if let Some(out) = &mut combined {
+                for _ in 0..github_column_count {
+                    rewrite_record.push_field(&[]);
+                }
+                push_received_date(&mut rewrite_record, stripe_row);
+                rewrite_record.push_field(&[]); /* distance */
+                if has_matched_by {
+                    rewrite_record.push_field(&[]); /* matched by */
+                }
+                if batch_window_seconds.is_some() {
+                    rewrite_record.push_field(&[]); /* group */
+                }
+                push_normalized(&mut rewrite_record, &stripe_row.row);
+                rewrite_record.push_field(&[]); /* confidence */
+                for _ in AMOUNT_COLUMNS {
+                    rewrite_record.push_field(&[]);
+                }
+                push_note(&mut rewrite_record);
+                rewrite_record.push_field(reason.as_str().as_bytes());
+                out.write_byte_record(&rewrite_record)?;
             }
-            out.write_byte_record(&rewrite_record)?;
+        }
+        for out in [combined, matched, unmatched_github, unmatched_stripe]
+            .iter_mut()
+            .flatten()
+        {
+            out.flush().map_err(csv::Error::from)?;
         }
 
-        Ok(())
+        Ok(Outcome {
+            github_conflicts,
+            changed_pairs,
+            num_greedy_github_rows,
+            num_matched,
+            num_unmatched_github_rows,
+            num_unmatched_stripe_rows,
+            num_ambiguous_github_rows,
+            explanations,
+            implausible_pairs,
+            auto_window,
+            unresolved_overrides,
+        })
     }
 
-    fn offset_of<'a>(
-        row: &'a StripeRow,
-        date_time: &time::OffsetDateTime,
-    ) -> (&'a StripeRow, time::Duration) {
-        (row, row.date_time - *date_time)
+    /// Return a score between 0 and 1 that is higher the closer a pair is in time relative to `max_distance_seconds`,
+    /// the more plausible its amounts are, and the farther away the next best candidate at `runner_up_distance` is.
+    fn confidence(
+        distance: u64,
+        max_distance_seconds: u64,
+        plausibility: Plausibility,
+        runner_up_distance: Option<u64>,
+    ) -> f64 {
+        let window = max_distance_seconds as f64 + 1.0;
+        let time = 1.0 - distance as f64 / window;
+        let amount = match plausibility {
+            Plausibility::Plausible => 1.0,
+            Plausibility::Unknown => 0.5,
+            Plausibility::Implausible => 0.1,
+        };
+        let uniqueness = runner_up_distance.map_or(1.0, |runner_up_distance| {
+            0.5 + 0.5 * runner_up_distance.saturating_sub(distance) as f64 / window
+        });
+        time * amount * uniqueness
+    }
+
+    /// Create a writer for `out` in the `dialect` and write `headers` to it.
+    fn writer<W: std::io::Write>(
+        dialect: &Dialect,
+        out: W,
+        headers: &csv::StringRecord,
+    ) -> Result<csv::Writer<W>, Error> {
+        let mut out = dialect.writer(out)?;
+        out.write_record(headers)?;
+        Ok(out)
+    }
+
+    struct GithubRow {
+        /// The date-time of the transaction.
+        date_time: time::OffsetDateTime,
+        /// The unaltered row itself.
+        row: csv::ByteRecord,
+    }
+
+    impl GithubRow {
+        fn key(&self, key_index: usize) -> String {
+            String::from_utf8_lossy(self.row.get(key_index).unwrap_or_default()).into_owned()
+        }
+    }
+
+    /// Parse `date_time` like GitHub writes it, for example `2021-12-28 14:13:14 +0000`.
+    pub(crate) fn parse_github_date_time(
+        date_time: &str,
+    ) -> Result<time::OffsetDateTime, gix_date::parse::Error> {
+        let date_time = gix_date::parse(date_time, None)?;
+        Ok(time::OffsetDateTime::from_unix_timestamp(date_time.seconds)
+            .expect("this should always work for reasonable times")
+            .to_offset(
+                time::UtcOffset::from_whole_seconds(date_time.offset).expect("reasonable offset"),
+            ))
+    }
+
+    /// The columns derived from the amounts of a pair, with the fee being the difference of the net amount
+    /// to the gross amount at the reference rate.
+    const AMOUNT_COLUMNS: [&str; 4] = ["Gross [USD]", "Net [Local]", "Implied Rate", "Fee [Local]"];
+
+    /// A group of GitHub rows paired with the same Stripe row.
+    #[derive(Clone, Copy)]
+    struct Batch {
+        /// The number of the group, starting at 1.
+        id: usize,
+        /// The sum of the amounts of all GitHub rows in the group.
+        gross: f64,
+    }
+
+    /// What is known about the amount of a Stripe row.
+    struct StripeAmount {
+        /// The amount received, in the local currency.
+        net: Option<f64>,
+        /// The rates of net per gross which are plausible.
+        band: Option<std::ops::RangeInclusive<f64>>,
+        /// The amount of the local currency worth one USD, if known.
+        reference_rate: Option<f64>,
     }
 
     struct StripeRow {
@@ -298,38 +1350,47 @@ pub(crate) mod function {
     }
 
     /// Returns a Vec sorted by utc_instant for binary searches.
+    ///
+    /// The date and the time, if there is a time column, are parsed with the first of `formats` that fits.
     fn into_stripe_lut(
         csv: &mut csv::Reader<&[u8]>,
         date_index: usize,
-        time_index: usize,
+        time_index: Option<usize>,
+        formats: &[Vec<time::format_description::FormatItem<'_>>],
     ) -> Result<Vec<StripeRow>, Error> {
         let mut out = Vec::new();
         let mut record = csv::ByteRecord::new();
-        static FORMAT: &[time::format_description::FormatItem<'static>] = time::macros::format_description!(
-            "[month repr:long] [day padding:none], [year][hour]:[minute]:[second] UTC"
-        );
         while csv.read_byte_record(&mut record)? {
             let date = record
                 .get(date_index)
                 .ok_or_else(|| Error::from_position(date_index, record.position(), "date"))?;
-            let time = record
-                .get(time_index)
-                .ok_or_else(|| Error::from_position(time_index, record.position(), "time"))?;
-
             let mut date_time = date.to_vec();
-            date_time.extend_from_slice(time);
+            if let Some(time_index) = time_index {
+                let time = record
+                    .get(time_index)
+                    .ok_or_else(|| Error::from_position(time_index, record.position(), "time"))?;
+                date_time.extend_from_slice(time);
+            }
             let date_time =
                 std::str::from_utf8(&date_time).map_err(|_| Error::InvalidDateEncoding {
                     date: String::from_utf8_lossy(&date_time).into_owned(),
                 })?;
-            let date_time = time::PrimitiveDateTime::parse(date_time, FORMAT).map_err(|err| {
-                Error::ParseTime {
-                    date_time: date_time.into(),
-                    source: err,
-                }
+            let mut last_err = None;
+            let parsed = formats.iter().find_map(|format| {
+                time::OffsetDateTime::parse(date_time, format)
+                    .or_else(|_| {
+                        time::PrimitiveDateTime::parse(date_time, format)
+                            .map(|date_time| date_time.assume_offset(time::UtcOffset::UTC))
+                    })
+                    .map_err(|err| last_err = Some(err))
+                    .ok()
+            });
+            let date_time = parsed.ok_or_else(|| Error::ParseTime {
+                date_time: date_time.into(),
+                source: last_err.expect("at least one format was tried"),
             })?;
             out.push(StripeRow {
-                date_time: date_time.assume_offset(time::UtcOffset::UTC),
+                date_time: date_time.to_offset(time::UtcOffset::UTC),
                 row: record.clone(),
             })
         }
@@ -0,0 +1,111 @@
+//! Judge whether the amount received on Stripe fits to the amount GitHub processed.
+use crate::merge_accounts::Error;
+use std::collections::BTreeMap;
+use std::ops::RangeInclusive;
+use std::path::Path;
+
+/// How well the net amount of a Stripe row fits to the gross amount of a GitHub row.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Plausibility {
+    /// The implied rate is within the plausible rates.
+    Plausible,
+    /// The implied rate is outside of the plausible rates.
+    Implausible,
+    /// One of the amounts couldn't be parsed, or there are no plausible rates to judge by.
+    Unknown,
+}
+
+/// The amounts of currency per USD by upper-case currency code, like `EUR`.
+pub(crate) type ReferenceRates = BTreeMap<String, f64>;
+
+/// Read the reference rates from the CSV file at `path` with a `Currency` and a `Rate` column,
+/// the latter being the amount of the currency that is worth one USD.
+pub(crate) fn reference_rates(path: &Path) -> Result<ReferenceRates, Error> {
+    let read_error = |source| Error::ReadReferenceRates {
+        path: path.into(),
+        source,
+    };
+    let mut rates = csv::Reader::from_path(path).map_err(read_error)?;
+    let headers = rates.headers().map_err(read_error)?.clone();
+    let column_idx = |column: &'static str| {
+        crate::merge::header_idx(column, &headers).ok_or_else(|| {
+            Error::MissingReferenceRateColumn {
+                path: path.into(),
+                column,
+            }
+        })
+    };
+    let (currency_idx, rate_idx) = (column_idx("Currency")?, column_idx("Rate")?);
+
+    let mut out = ReferenceRates::new();
+    for record in rates.records() {
+        let record = record.map_err(read_error)?;
+        let (currency, rate) = (
+            record.get(currency_idx).unwrap_or_default(),
+            record.get(rate_idx).unwrap_or_default(),
+        );
+        let parsed = crate::parse_rate(rate.as_bytes()).filter(|rate| *rate > 0.0);
+        let Some(parsed) = parsed else {
+            return Err(Error::InvalidReferenceRate {
+                path: path.into(),
+                currency: currency.into(),
+                rate: rate.into(),
+            });
+        };
+        out.insert(currency.trim().to_ascii_uppercase(), parsed);
+    }
+    Ok(out)
+}
+
+/// The rates relative to the reference rate which are plausible if no others are given.
+const DEFAULT_RELATIVE_RATES: RangeInclusive<f64> = 0.5..=1.5;
+
+/// Return the rates of net per gross which are plausible for a Stripe row in `currency`.
+///
+/// If there are `reference_rates`, `plausible_rates` is relative to the reference rate of `currency`, or
+/// [`DEFAULT_RELATIVE_RATES`] if unset, and there is no band if the reference rate is unknown.
+/// Otherwise, there is only a band if `plausible_rates` is set.
+pub(crate) fn band(
+    plausible_rates: Option<&RangeInclusive<f64>>,
+    currency: Option<&[u8]>,
+    reference_rates: Option<&ReferenceRates>,
+) -> Option<RangeInclusive<f64>> {
+    match reference_rates {
+        Some(reference_rates) => {
+            let reference_rate = reference_rate(currency, reference_rates)?;
+            let relative = plausible_rates.unwrap_or(&DEFAULT_RELATIVE_RATES);
+            Some(relative.start() * reference_rate..=relative.end() * reference_rate)
+        }
+        None => plausible_rates.cloned(),
+    }
+}
+
+/// Return the reference rate for `currency`, if it is known.
+pub(crate) fn reference_rate(
+    currency: Option<&[u8]>,
+    reference_rates: &ReferenceRates,
+) -> Option<f64> {
+    let currency = std::str::from_utf8(currency?).ok()?;
+    reference_rates
+        .get(&currency.trim().to_ascii_uppercase())
+        .copied()
+}
+
+/// Return the rate implied by `net / gross`, if both are known and `gross` isn't zero.
+pub(crate) fn rate(gross: Option<f64>, net: Option<f64>) -> Option<f64> {
+    let (gross, net) = (gross?, net?);
+    (gross != 0.0).then(|| net / gross)
+}
+
+/// Judge the rate implied by `net / gross` by `band`.
+pub(crate) fn plausibility(
+    gross: Option<f64>,
+    net: Option<f64>,
+    band: Option<&RangeInclusive<f64>>,
+) -> Plausibility {
+    match (rate(gross, net), band) {
+        (Some(rate), Some(band)) if band.contains(&rate) => Plausibility::Plausible,
+        (Some(_), Some(_)) => Plausibility::Implausible,
+        _ => Plausibility::Unknown,
+    }
+}
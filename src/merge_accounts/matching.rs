@@ -0,0 +1,420 @@
+//! Pair GitHub transactions with the Stripe transactions that were booked shortly after them.
+use std::collections::BTreeMap;
+use time::OffsetDateTime;
+
+/// The seconds a Stripe row may be booked before or after a GitHub row to be a candidate for it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Window {
+    pub before_seconds: u64,
+    pub after_seconds: u64,
+}
+
+impl Window {
+    /// Return the most seconds a Stripe row may be away from a GitHub row in the direction of `offset_seconds`.
+    pub fn bound(&self, offset_seconds: i64) -> u64 {
+        if offset_seconds < 0 {
+            self.before_seconds
+        } else {
+            self.after_seconds
+        }
+    }
+}
+
+/// Return the index of the Stripe row that each GitHub row is paired with, if any.
+///
+/// Each GitHub row, from earliest to latest, takes the closest Stripe row within `window` that wasn't taken by a previous
+/// GitHub row, the earlier one if two are equally close, with GitHub rows of the same time in the order of `github`.
+/// `stripe` must be sorted.
+///
+/// Pairs of GitHub and Stripe row indices for which `is_compatible` returns `false` are never formed.
+pub(crate) fn greedy(
+    github: &[OffsetDateTime],
+    stripe: &[OffsetDateTime],
+    window: Window,
+    is_compatible: impl Fn(usize, usize) -> bool,
+) -> Vec<Option<usize>> {
+    let mut order: Vec<usize> = (0..github.len()).collect();
+    order.sort_by_key(|github_idx| github[*github_idx]);
+    let mut taken = vec![false; stripe.len()];
+    let mut out = vec![None; github.len()];
+    for github_idx in order {
+        out[github_idx] = candidates(&github[github_idx], stripe, window)
+            .filter(|(idx, _offset)| !taken[*idx] && is_compatible(github_idx, *idx))
+            .min_by_key(|(_idx, offset)| offset.unsigned_abs())
+            .map(|(idx, _offset)| idx);
+        if let Some(idx) = out[github_idx] {
+            taken[idx] = true;
+        }
+    }
+    out
+}
+
+/// Return the index of the Stripe row whose `stripe_ids` field contains the ID of each GitHub row in `github_ids`, if any.
+///
+/// IDs are compared to each word of the Stripe field, so descriptions like `Payment for ch_123` match too.
+/// Each Stripe row is paired at most once, with the first GitHub row whose ID it contains, and empty IDs never match.
+pub(crate) fn by_id(github_ids: &[&[u8]], stripe_ids: &[&[u8]]) -> Vec<Option<usize>> {
+    let mut github_idx_by_id = BTreeMap::<&[u8], Vec<usize>>::new();
+    for (github_idx, id) in github_ids.iter().enumerate() {
+        let id = id.trim_ascii();
+        if !id.is_empty() {
+            github_idx_by_id.entry(id).or_default().push(github_idx);
+        }
+    }
+    let mut out = vec![None; github_ids.len()];
+    for (stripe_idx, field) in stripe_ids.iter().enumerate() {
+        let github_idx = field
+            .split(|b| !(b.is_ascii_alphanumeric() || *b == b'_' || *b == b'-'))
+            .filter_map(|word| github_idx_by_id.get(word))
+            .flatten()
+            .find(|github_idx| out[**github_idx].is_none());
+        if let Some(github_idx) = github_idx {
+            out[*github_idx] = Some(stripe_idx);
+        }
+    }
+    out
+}
+
+/// Return the pairs of GitHub and Stripe row indices which are each other's only compatible candidate within `window`.
+pub(crate) fn unambiguous(
+    github: &[OffsetDateTime],
+    stripe: &[OffsetDateTime],
+    window: Window,
+    is_compatible: impl Fn(usize, usize) -> bool,
+) -> Vec<(usize, usize)> {
+    let candidates: Vec<Vec<usize>> = github
+        .iter()
+        .enumerate()
+        .map(|(github_idx, github)| {
+            candidates(github, stripe, window)
+                .map(|(stripe_idx, _offset)| stripe_idx)
+                .filter(|stripe_idx| is_compatible(github_idx, *stripe_idx))
+                .collect()
+        })
+        .collect();
+    let mut num_github_rows = vec![0usize; stripe.len()];
+    for stripe_idx in candidates.iter().flatten() {
+        num_github_rows[*stripe_idx] += 1;
+    }
+    candidates
+        .iter()
+        .enumerate()
+        .filter_map(|(github_idx, candidates)| match candidates.as_slice() {
+            [stripe_idx] if num_github_rows[*stripe_idx] == 1 => Some((github_idx, *stripe_idx)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// The percentiles of the lags of unambiguous pairs that are reported along with an automatically chosen window.
+pub(crate) const LAG_PERCENTILES: [u8; 4] = [50, 90, 95, 99];
+
+/// Return the smallest value of `sorted` that is at least as large as `percentile` percent of its values, if it isn't empty.
+pub(crate) fn percentile(sorted: &[i64], percentile: u8) -> Option<i64> {
+    let rank = (sorted.len() * usize::from(percentile)).div_ceil(100);
+    sorted.get(rank.max(1) - 1).copied()
+}
+
+/// The most GitHub rows that share candidates with each other which are paired optimally, as the time it takes grows
+/// with the cube of their amount.
+pub(crate) const MAX_OPTIMAL_ROWS: usize = 256;
+
+/// Like [`greedy()`], but pair as many GitHub rows as possible such that the sum of all distances is the smallest,
+/// regardless of whether the Stripe rows were booked before or after.
+///
+/// Among equally good solutions, the one with the least pairs that differ from `greedy` is chosen.
+/// GitHub rows that share candidates with more than [`MAX_OPTIMAL_ROWS`] rows keep their `greedy` pair,
+/// and their amount is returned as well.
+pub(crate) fn optimal(
+    github: &[OffsetDateTime],
+    stripe: &[OffsetDateTime],
+    window: Window,
+    is_compatible: impl Fn(usize, usize) -> bool,
+    greedy: &[Option<usize>],
+) -> (Vec<Option<usize>>, usize) {
+    let candidates: Vec<Vec<(usize, u64)>> = github
+        .iter()
+        .enumerate()
+        .map(|(github_idx, github)| {
+            candidates(github, stripe, window)
+                .filter(|(stripe_idx, _offset)| is_compatible(github_idx, *stripe_idx))
+                .map(|(stripe_idx, offset)| (stripe_idx, offset.unsigned_abs()))
+                .collect()
+        })
+        .collect();
+
+    // GitHub rows that share a candidate influence each other, all others can be solved separately.
+    let mut parent: Vec<usize> = (0..github.len()).collect();
+    let mut first_github_row_of_stripe_row = vec![None; stripe.len()];
+    for (github_idx, candidates) in candidates.iter().enumerate() {
+        for (stripe_idx, _distance) in candidates {
+            match first_github_row_of_stripe_row[*stripe_idx] {
+                Some(other) => {
+                    let (a, b) = (root(&mut parent, other), root(&mut parent, github_idx));
+                    parent[a] = b;
+                }
+                None => first_github_row_of_stripe_row[*stripe_idx] = Some(github_idx),
+            }
+        }
+    }
+    let mut components = BTreeMap::<usize, Vec<usize>>::new();
+    for (github_idx, candidates) in candidates.iter().enumerate() {
+        if !candidates.is_empty() {
+            let root = root(&mut parent, github_idx);
+            components.entry(root).or_default().push(github_idx);
+        }
+    }
+
+    let mut out = vec![None; github.len()];
+    let mut num_greedy = 0;
+    for rows in components.into_values() {
+        // Their candidates aren't shared with any other component, so the greedy pairs don't collide with other pairs.
+        if rows.len() > MAX_OPTIMAL_ROWS {
+            for github_idx in &rows {
+                out[*github_idx] = greedy[*github_idx];
+            }
+            num_greedy += rows.len();
+            continue;
+        }
+        let mut columns: Vec<usize> = rows
+            .iter()
+            .flat_map(|row| candidates[*row].iter().map(|(stripe_idx, _)| *stripe_idx))
+            .collect();
+        columns.sort_unstable();
+        columns.dedup();
+
+        // Scale distances so that preferring the greedy pair only decides between solutions of the same total distance,
+        // and make leaving a row unpaired more expensive than any solution that pairs it.
+        let n = rows.len() as i128;
+        let scale = n + 1;
+        let max_distance_seconds = window.before_seconds.max(window.after_seconds);
+        let unpaired = (max_distance_seconds as i128 * scale + 1) * n + 1;
+        let impossible = unpaired * (n + 1);
+        let cost: Vec<Vec<i128>> = rows
+            .iter()
+            .enumerate()
+            .map(|(row_idx, github_idx)| {
+                let paired = columns.iter().map(|stripe_idx| {
+                    candidates[*github_idx]
+                        .iter()
+                        .find(|(idx, _)| idx == stripe_idx)
+                        .map_or(impossible, |(_, distance)| {
+                            *distance as i128 * scale
+                                + i128::from(greedy[*github_idx] != Some(*stripe_idx))
+                        })
+                });
+                let unpaired_columns =
+                    (0..rows.len()).map(|idx| if idx == row_idx { unpaired } else { impossible });
+                paired.chain(unpaired_columns).collect()
+            })
+            .collect();
+        for (github_idx, column) in rows.iter().zip(assignment(&cost)) {
+            out[*github_idx] = columns.get(column).copied();
+        }
+    }
+    (out, num_greedy)
+}
+
+/// The most GitHub rows closest to a Stripe row that are considered to be part of a batch, to bound the amount of groups to try.
+const MAX_BATCH_CANDIDATES: usize = 12;
+
+/// Return groups of at least two and at most `max_size` GitHub rows along with a Stripe row, none of which are paired in `pairs`,
+/// such that the Stripe row was booked at most `window_seconds` after each GitHub row of its group.
+///
+/// `fit` returns a score for the GitHub rows of a group and a Stripe row if their amounts fit, and the group with the lowest
+/// score is chosen for each Stripe row, earliest first. Only the [`MAX_BATCH_CANDIDATES`] GitHub rows closest to a Stripe row
+/// are considered.
+pub(crate) fn batches(
+    github: &[OffsetDateTime],
+    stripe: &[OffsetDateTime],
+    window_seconds: u64,
+    max_size: usize,
+    pairs: &[Option<usize>],
+    fit: impl Fn(&[usize], usize) -> Option<f64>,
+) -> Vec<(Vec<usize>, usize)> {
+    let mut is_paired_github: Vec<_> = pairs.iter().map(Option::is_some).collect();
+    let mut is_paired_stripe = vec![false; stripe.len()];
+    for stripe_idx in pairs.iter().flatten() {
+        is_paired_stripe[*stripe_idx] = true;
+    }
+    let mut out = Vec::new();
+    for (stripe_idx, stripe_time) in stripe.iter().enumerate() {
+        if is_paired_stripe[stripe_idx] {
+            continue;
+        }
+        let mut members: Vec<_> = github
+            .iter()
+            .enumerate()
+            .filter(|(github_idx, github)| {
+                !is_paired_github[*github_idx]
+                    && *github <= stripe_time
+                    && (*stripe_time - **github).whole_seconds().unsigned_abs() <= window_seconds
+            })
+            .map(|(github_idx, _)| github_idx)
+            .collect();
+        members.sort_by_key(|github_idx| std::cmp::Reverse(github[*github_idx]));
+        members.truncate(MAX_BATCH_CANDIDATES);
+
+        let mut best: Option<(f64, Vec<usize>)> = None;
+        let mut group = Vec::with_capacity(max_size);
+        for mask in 0u32..1 << members.len() {
+            let size = mask.count_ones() as usize;
+            if size < 2 || size > max_size {
+                continue;
+            }
+            group.clear();
+            group.extend(
+                members
+                    .iter()
+                    .enumerate()
+                    .filter(|(bit, _)| mask & (1 << bit) != 0)
+                    .map(|(_, github_idx)| *github_idx),
+            );
+            let Some(score) = fit(&group, stripe_idx) else {
+                continue;
+            };
+            let is_better = best.as_ref().is_none_or(|(best_score, best_group)| {
+                (score, group.len()) < (*best_score, best_group.len())
+            });
+            if is_better {
+                best = Some((score, group.clone()));
+            }
+        }
+        if let Some((_, mut group)) = best {
+            group.sort_unstable();
+            for github_idx in &group {
+                is_paired_github[*github_idx] = true;
+            }
+            out.push((group, stripe_idx));
+        }
+    }
+    out
+}
+
+/// Return for each Stripe row whether it was booked within `window` of any GitHub row,
+/// and whether it is compatible with any of these.
+pub(crate) fn is_candidate(
+    github: &[OffsetDateTime],
+    stripe: &[OffsetDateTime],
+    window: Window,
+    is_compatible: impl Fn(usize, usize) -> bool,
+) -> Vec<(bool, bool)> {
+    let mut out = vec![(false, false); stripe.len()];
+    for (github_idx, github) in github.iter().enumerate() {
+        for (idx, _offset) in candidates(github, stripe, window) {
+            out[idx].0 = true;
+            out[idx].1 |= is_compatible(github_idx, idx);
+        }
+    }
+    out
+}
+
+/// Return the index and signed offset in seconds of the closest Stripe row booked before the `window` around `github`,
+/// of all candidates, and of the first Stripe row booked after the `window`, earliest first.
+pub(crate) fn neighbourhood<'a>(
+    github: &'a OffsetDateTime,
+    stripe: &'a [OffsetDateTime],
+    window: Window,
+) -> impl Iterator<Item = (usize, i64)> + 'a {
+    let first = first_candidate(github, stripe, window);
+    let num_candidates = candidates(github, stripe, window).count();
+    (first.saturating_sub(1)..(first + num_candidates + 1).min(stripe.len()))
+        .map(move |idx| (idx, (stripe[idx] - *github).whole_seconds()))
+}
+
+/// Return the index and signed offset in seconds of all Stripe rows that were booked within `window` of `github`,
+/// earliest first. The offset is negative if the Stripe row was booked first.
+pub(crate) fn candidates<'a>(
+    github: &'a OffsetDateTime,
+    stripe: &'a [OffsetDateTime],
+    window: Window,
+) -> impl Iterator<Item = (usize, i64)> + 'a {
+    let first = first_candidate(github, stripe, window);
+    stripe[first..]
+        .iter()
+        .enumerate()
+        .map(move |(idx, stripe)| (first + idx, (*stripe - *github).whole_seconds()))
+        .take_while(move |(_, offset)| {
+            *offset <= 0 || offset.unsigned_abs() <= window.after_seconds
+        })
+}
+
+/// Return the index of the first Stripe row booked at most `window.before_seconds` before `github`.
+fn first_candidate(github: &OffsetDateTime, stripe: &[OffsetDateTime], window: Window) -> usize {
+    stripe.partition_point(|stripe| {
+        *stripe < *github
+            && (*github - *stripe).whole_seconds().unsigned_abs() > window.before_seconds
+    })
+}
+
+fn root(parent: &mut [usize], mut idx: usize) -> usize {
+    while parent[idx] != idx {
+        parent[idx] = parent[parent[idx]];
+        idx = parent[idx];
+    }
+    idx
+}
+
+/// Solve the assignment problem for `cost` with the Hungarian algorithm, and return the column assigned to each row
+/// such that the sum of their costs is minimal. Each row must have at least as many columns as there are rows.
+fn assignment(cost: &[Vec<i128>]) -> Vec<usize> {
+    let rows = cost.len();
+    let columns = cost.first().map_or(0, Vec::len);
+    // Potentials and the row assigned to each column are 1-based, with column 0 being a virtual starting point.
+    let mut row_potential = vec![0i128; rows + 1];
+    let mut column_potential = vec![0i128; columns + 1];
+    let mut row_of_column = vec![0usize; columns + 1];
+    let mut previous_column = vec![0usize; columns + 1];
+    for row in 1..=rows {
+        row_of_column[0] = row;
+        let mut column = 0;
+        let mut min_slack = vec![i128::MAX; columns + 1];
+        let mut visited = vec![false; columns + 1];
+        loop {
+            visited[column] = true;
+            let current_row = row_of_column[column];
+            let mut delta = i128::MAX;
+            let mut next_column = 0;
+            for candidate in 1..=columns {
+                if visited[candidate] {
+                    continue;
+                }
+                let slack = cost[current_row - 1][candidate - 1]
+                    - row_potential[current_row]
+                    - column_potential[candidate];
+                if slack < min_slack[candidate] {
+                    min_slack[candidate] = slack;
+                    previous_column[candidate] = column;
+                }
+                if min_slack[candidate] < delta {
+                    delta = min_slack[candidate];
+                    next_column = candidate;
+                }
+            }
+            for candidate in 0..=columns {
+                if visited[candidate] {
+                    row_potential[row_of_column[candidate]] += delta;
+                    column_potential[candidate] -= delta;
+                } else {
+                    min_slack[candidate] -= delta;
+                }
+            }
+            column = next_column;
+            if row_of_column[column] == 0 {
+                break;
+            }
+        }
+        while column != 0 {
+            let previous = previous_column[column];
+            row_of_column[column] = row_of_column[previous];
+            column = previous;
+        }
+    }
+    let mut out = vec![0; rows];
+    for (column, row) in row_of_column.iter().enumerate().skip(1) {
+        if *row != 0 {
+            out[row - 1] = column - 1;
+        }
+    }
+    out
+}
@@ -0,0 +1,192 @@
+//! Decisions about rows that automatic matching can't make, read from a file and applied before any matching.
+use crate::merge_accounts::function::parse_github_date_time;
+use crate::merge_accounts::Error;
+use std::path::Path;
+use time::OffsetDateTime;
+
+/// The contents of an overrides file.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct Overrides {
+    pub overrides: Vec<Override>,
+}
+
+/// A decision about how a row is paired, like for delayed settlements or refunds that were charged again.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum Override {
+    /// Pair the GitHub row with `transaction_id` with `stripe_row`.
+    Pin {
+        transaction_id: String,
+        stripe_row: StripeRow,
+    },
+    /// Never pair the GitHub row with `transaction_id`.
+    UnmatchedGithubRow { transaction_id: String },
+    /// Never pair `stripe_row`.
+    UnmatchedStripeRow { stripe_row: StripeRow },
+}
+
+/// How a Stripe row is identified.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum StripeRow {
+    /// The row with this value in the [row ID column](crate::merge_accounts::Options::stripe_row_id_column).
+    Id(String),
+    /// The row booked at `date_time`, like `2021-12-28 14:13:14 +0000`, with `amount` in the Stripe amount column.
+    DateTime { date_time: String, amount: f64 },
+}
+
+/// Why an override couldn't be applied.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnresolvedReason {
+    /// There is no GitHub row with its transaction ID.
+    NoGithubRow,
+    /// There is no Stripe row with its ID, or its date-time and amount.
+    NoStripeRow,
+    /// Its rows were already referenced by a previous override.
+    AlreadyReferenced,
+}
+
+impl UnresolvedReason {
+    /// Return a human-readable description of the reason.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            UnresolvedReason::NoGithubRow => "references no GitHub row",
+            UnresolvedReason::NoStripeRow => "references no stripe row",
+            UnresolvedReason::AlreadyReferenced => "references a row of a previous override",
+        }
+    }
+}
+
+/// An override that wasn't applied.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Unresolved {
+    /// The position of the override in the overrides file, starting at 0.
+    pub index: usize,
+    /// Why it wasn't applied.
+    pub reason: UnresolvedReason,
+}
+
+/// The rows that overrides decided about, by index.
+pub(crate) struct Resolved {
+    /// The Stripe row each GitHub row is pinned to, if any.
+    pub pinned: Vec<Option<usize>>,
+    /// Whether each GitHub row must not be paired.
+    pub is_unmatched_github: Vec<bool>,
+    /// Whether each Stripe row must not be paired.
+    pub is_unmatched_stripe: Vec<bool>,
+    /// The overrides that reference nothing.
+    pub unresolved: Vec<Unresolved>,
+}
+
+/// Find the rows that each of `overrides`, read from the file at the given path, references, by the key of each GitHub row
+/// in `github_keys`, and by the ID in `stripe_ids` if there is a column for it, or the date-time and the amount of each Stripe row.
+pub(crate) fn resolve(
+    overrides: Option<(&Path, &[Override])>,
+    github_keys: &[&[u8]],
+    stripe_ids: Option<&[&[u8]]>,
+    stripe_times: &[OffsetDateTime],
+    stripe_amounts: &[Option<f64>],
+) -> Result<Resolved, Error> {
+    let mut out = Resolved {
+        pinned: vec![None; github_keys.len()],
+        is_unmatched_github: vec![false; github_keys.len()],
+        is_unmatched_stripe: vec![false; stripe_times.len()],
+        unresolved: Vec::new(),
+    };
+    let Some((path, overrides)) = overrides else {
+        return Ok(out);
+    };
+    let mut is_referenced_github = vec![false; github_keys.len()];
+    let mut is_referenced_stripe = vec![false; stripe_times.len()];
+    let first_unreferenced = |matches: Vec<usize>, is_referenced: &[bool], none| match matches
+        .iter()
+        .find(|idx| !is_referenced[**idx])
+    {
+        Some(idx) => Ok(*idx),
+        None if matches.is_empty() => Err(none),
+        None => Err(UnresolvedReason::AlreadyReferenced),
+    };
+    let github_row = |transaction_id: &str, is_referenced: &[bool]| {
+        let matches = github_keys
+            .iter()
+            .enumerate()
+            .filter(|(_, key)| key.trim_ascii() == transaction_id.trim().as_bytes())
+            .map(|(idx, _)| idx)
+            .collect();
+        first_unreferenced(matches, is_referenced, UnresolvedReason::NoGithubRow)
+    };
+    let stripe_row = |index, stripe_row: &StripeRow, is_referenced: &[bool]| -> Result<_, Error> {
+        let matches = match stripe_row {
+            StripeRow::Id(id) => stripe_ids
+                .unwrap_or_default()
+                .iter()
+                .enumerate()
+                .filter(|(_, stripe_id)| stripe_id.trim_ascii() == id.trim().as_bytes())
+                .map(|(idx, _)| idx)
+                .collect(),
+            StripeRow::DateTime { date_time, amount } => {
+                let date_time = parse_github_date_time(date_time).map_err(|source| {
+                    Error::InvalidOverrideDateTime {
+                        path: path.to_owned(),
+                        index,
+                        date_time: date_time.clone(),
+                        source,
+                    }
+                })?;
+                stripe_times
+                    .iter()
+                    .zip(stripe_amounts)
+                    .enumerate()
+                    .filter(|(_, (stripe_time, stripe_amount))| {
+                        **stripe_time == date_time
+                            && stripe_amount
+                                .is_some_and(|stripe_amount| (stripe_amount - amount).abs() < 0.005)
+                    })
+                    .map(|(idx, _)| idx)
+                    .collect()
+            }
+        };
+        Ok(first_unreferenced(
+            matches,
+            is_referenced,
+            UnresolvedReason::NoStripeRow,
+        ))
+    };
+
+    for (index, r#override) in overrides.iter().enumerate() {
+        let (github_idx, stripe_idx) = match r#override {
+            Override::Pin {
+                transaction_id,
+                stripe_row: row,
+            } => (
+                Some(github_row(transaction_id, &is_referenced_github)),
+                Some(stripe_row(index, row, &is_referenced_stripe)?),
+            ),
+            Override::UnmatchedGithubRow { transaction_id } => (
+                Some(github_row(transaction_id, &is_referenced_github)),
+                None,
+            ),
+            Override::UnmatchedStripeRow { stripe_row: row } => {
+                (None, Some(stripe_row(index, row, &is_referenced_stripe)?))
+            }
+        };
+        let (github_idx, stripe_idx) = match (github_idx.transpose(), stripe_idx.transpose()) {
+            (Ok(github_idx), Ok(stripe_idx)) => (github_idx, stripe_idx),
+            (Err(reason), _) | (_, Err(reason)) => {
+                out.unresolved.push(Unresolved { index, reason });
+                continue;
+            }
+        };
+        match (github_idx, stripe_idx) {
+            (Some(github_idx), Some(stripe_idx)) => out.pinned[github_idx] = Some(stripe_idx),
+            (Some(github_idx), None) => out.is_unmatched_github[github_idx] = true,
+            (None, Some(stripe_idx)) => out.is_unmatched_stripe[stripe_idx] = true,
+            (None, None) => unreachable!("each override references at least one row"),
+        }
+        if let Some(github_idx) = github_idx {
+            is_referenced_github[github_idx] = true;
+        }
+        if let Some(stripe_idx) = stripe_idx {
+            is_referenced_stripe[stripe_idx] = true;
+        }
+    }
+    Ok(out)
+}
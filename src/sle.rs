@@ -56,8 +56,6 @@ impl Rule {
 
 impl Engine {
     pub fn matching_rule(&self, record: &csv::ByteRecord) -> Option<&Rule> {
-        self.rules
-            .iter()
-            .find_map(|rule| rule.matches(record).then_some(rule))
+        self.rules.iter().find(|rule| rule.matches(record))
     }
 }
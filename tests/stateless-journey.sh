@@ -2,6 +2,7 @@
 set -eu
 
 exe=${1:?First argument must be the executable to test}
+exe="$(cd "$(dirname "$exe")" && pwd)/$(basename "$exe")"
 
 root="$(cd "${0%/*}" && pwd)"
 # shellcheck disable=1090
@@ -27,6 +28,112 @@ WITH_FAILURE=2
       expect_run ${SUCCESSFULLY} "$exe" merge 'Transaction Date' 'Transaction Date' "$fixture/sponsors-2021.csv" "$fixture/sponsors-2022.csv"
     }
   )
+  (with "a date sort column and a date format"
+    it "sorts chronologically" && {
+      WITH_SNAPSHOT="$snapshot/success-sort-by-date.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge --sort-type date --date-format '[month repr:long] [day padding:none], [year]' Time Date "$fixture/stripe-activity.csv"
+    }
+  )
+  (with "a date sort column but no date format"
+    it "fails as the format is required" && {
+      WITH_SNAPSHOT="$snapshot/fail-sort-by-date-without-format" \
+      expect_run ${WITH_ERROR} "$exe" merge --sort-type date Time Date "$fixture/stripe-activity.csv"
+    }
+  )
+  (with "a configuration file in the working directory"
+    workdir="$(mktemp -d)"
+    trap 'rm -rf "$workdir"' EXIT
+    cp "$fixture/profiles.toml" "$workdir/stool.toml"
+    cd "$workdir"
+    it "sorts as configured by the profile" && {
+      WITH_SNAPSHOT="$snapshot/success-config-profile.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge --profile utc Time Date "$fixture/stripe-activity.csv"
+    }
+    it "fails naming the key that made the missing value required" && {
+      WITH_SNAPSHOT="$snapshot/fail-config-missing-date-format" \
+      expect_run ${WITH_ERROR} "$exe" merge Time Date "$fixture/stripe-activity.csv"
+    }
+  )
+  (with "--provenance"
+    it "appends columns telling where each row came from" && {
+      cd "$fixture"
+      WITH_SNAPSHOT="$snapshot/success-provenance.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge --provenance Time Time stripe-activity.csv stripe-activity-update.csv
+    }
+  )
+  (with "files whose columns differ"
+    (with "no further options"
+      it "fails as the schema changed" && {
+        WITH_SNAPSHOT="$snapshot/fail-schema-change" \
+        expect_run ${WITH_ERROR} "$exe" merge 'Transaction ID' 'Transaction Date' "$fixture/sponsors-2020.csv" "$fixture/sponsors-2021.csv"
+      }
+    )
+    (with "--union-columns"
+      it "lines up columns by name and reports missing ones" && {
+        cd "$fixture"
+        WITH_SNAPSHOT="$snapshot/success-union-columns.csv" \
+        expect_run ${SUCCESSFULLY} "$exe" merge --union-columns 'Transaction ID' 'Transaction Date' sponsors-2020.csv sponsors-2021.csv
+      }
+    )
+  )
+  (with "rows that changed between files"
+    (with "the default conflict policy and a conflict report"
+      it "keeps the last row and reports each changed field" && {
+        WITH_SNAPSHOT="$snapshot/success-conflict-report" \
+        expect_run ${SUCCESSFULLY} "$exe" merge --conflict-report /dev/stderr Time Time "$fixture/stripe-activity.csv" "$fixture/stripe-activity-update.csv"
+      }
+    )
+    (with "a conflict policy preferring a column value"
+      it "keeps the row with the most preferred value" && {
+        WITH_SNAPSHOT="$snapshot/success-conflict-prefer-status.csv" \
+        expect_run ${SUCCESSFULLY} "$exe" merge --on-conflict prefer --prefer-column Status --prefer-values Pending,Settled,Refunded Time Time "$fixture/stripe-activity.csv" "$fixture/stripe-activity-update.csv" "$fixture/stripe-activity-outdated.csv"
+      }
+    )
+    (with "a conflict policy that disallows conflicts"
+      it "fails" && {
+        cd "$fixture"
+        WITH_SNAPSHOT="$snapshot/fail-conflict" \
+        expect_run ${WITH_ERROR} "$exe" merge --on-conflict error Time Time stripe-activity.csv stripe-activity-update.csv
+      }
+    )
+  )
+)
+
+(when "adding exports to an archive"
+  snapshot="$snapshot/archive"
+  workdir="$(mktemp -d)"
+  trap 'rm -rf "$workdir"' EXIT
+  cd "$workdir"
+  cp "$fixture"/stripe-activity.csv "$fixture"/stripe-activity-update.csv .
+  (with "an archive that doesn't exist yet"
+    it "creates it with all rows of the export" && {
+      WITH_SNAPSHOT="$snapshot/success-create" \
+      expect_run ${SUCCESSFULLY} "$exe" archive add Time Time archive.csv stripe-activity.csv
+    }
+  )
+  (with "an export with new and changed rows"
+    it "counts added and replaced rows" && {
+      WITH_SNAPSHOT="$snapshot/success-add-and-replace" \
+      expect_run ${SUCCESSFULLY} "$exe" archive add Time Time archive.csv stripe-activity-update.csv
+    }
+    it "produces the merged archive" && {
+      WITH_SNAPSHOT="$snapshot/success-archive.csv" \
+      expect_run ${SUCCESSFULLY} cat archive.csv
+    }
+  )
+  (with "an archive with two rows of the same key"
+    { cat "$fixture"/stripe-activity.csv; tail -n 1 "$fixture"/stripe-activity.csv; } > edited-archive.csv
+    it "counts only rows of keys that weren't archived as added" && {
+      WITH_SNAPSHOT="$snapshot/success-add-to-archive-with-duplicates" \
+      expect_run ${SUCCESSFULLY} "$exe" archive add Time Time edited-archive.csv stripe-activity-update.csv
+    }
+  )
+  (with "exports that were added before"
+    it "skips them" && {
+      WITH_SNAPSHOT="$snapshot/success-skip-known-exports" \
+      expect_run ${SUCCESSFULLY} "$exe" archive add Time Time archive.csv stripe-activity.csv stripe-activity-update.csv
+    }
+  )
 )
 
 (when "merging account data"
@@ -87,4 +194,180 @@ WITH_FAILURE=2
       }
     )
   )
+
+  (with "two github account files and one stripe activity feed and a file per category"
+    workdir="$(mktemp -d)"
+    trap 'rm -rf "$workdir"' EXIT
+    it "writes nothing to stdout" && {
+      WITH_SNAPSHOT="$snapshot/success-split-output" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --matched "$workdir/matched.csv" --unmatched-github "$workdir/unmatched-github.csv" --unmatched-stripe "$workdir/unmatched-stripe.csv" --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+    it "writes the paired rows to the matched file" && {
+      WITH_SNAPSHOT="$snapshot/success-matched.csv" \
+      expect_run ${SUCCESSFULLY} cat "$workdir/matched.csv"
+    }
+    it "writes the GitHub rows without stripe row to their file" && {
+      WITH_SNAPSHOT="$snapshot/success-unmatched-github.csv" \
+      expect_run ${SUCCESSFULLY} cat "$workdir/unmatched-github.csv"
+    }
+    it "writes the stripe rows without GitHub row along with the reason to their file" && {
+      WITH_SNAPSHOT="$snapshot/success-unmatched-stripe.csv" \
+      expect_run ${SUCCESSFULLY} cat "$workdir/unmatched-stripe.csv"
+    }
+  )
+  (with "two github account files and one stripe activity feed and a file for unmatched stripe rows only"
+    workdir="$(mktemp -d)"
+    trap 'rm -rf "$workdir"' EXIT
+    it "writes the GitHub rows to stdout like the combined output" && {
+      WITH_SNAPSHOT="$snapshot/success-partially-split-output.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --unmatched-stripe "$workdir/unmatched-stripe.csv" --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+    it "writes the stripe rows without GitHub row to their file" && {
+      WITH_SNAPSHOT="$snapshot/success-unmatched-stripe.csv" \
+      expect_run ${SUCCESSFULLY} cat "$workdir/unmatched-stripe.csv"
+    }
+  )
+  (with "one github account file and one stripe activity feed and --explain"
+    it "explains on stderr which stripe rows were looked at for each GitHub row" && {
+      WITH_SNAPSHOT="$snapshot/success-explain" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --explain --matched /dev/null --unmatched-github /dev/null --unmatched-stripe /dev/null -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+    it "writes the explanation as JSON lines if a path is given" && {
+      WITH_SNAPSHOT="$snapshot/success-explain.jsonl" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --explain /dev/stdout --matched /dev/null --unmatched-github /dev/null --unmatched-stripe /dev/null -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+  )
+  (with "overlapping github account files with a changed transaction and one paid in the same second as another"
+    it "keeps both transactions of the same second and warns about the changed one" && {
+      cd "$fixture"
+      WITH_SNAPSHOT="$snapshot/success-overlapping-github-activity" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts -g sponsors-2022.csv -g sponsors-2022-update.csv --stripe-activity stripe-activity.csv
+    }
+  )
+  (with "overlapping github account files with a changed transaction and a conflict policy that disallows conflicts"
+    it "fails" && {
+      cd "$fixture"
+      WITH_SNAPSHOT="$snapshot/fail-github-conflict" \
+      expect_run ${WITH_ERROR} "$exe" merge-accounts --on-conflict error -g sponsors-2022.csv -g sponsors-2022-update.csv --stripe-activity stripe-activity.csv
+    }
+  )
+  (with "two github account files and one stripe activity feed with the date and time in one column"
+    it "parses the date and time with the given format" && {
+      WITH_SNAPSHOT="$snapshot/success-stripe-date-time-column.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --stripe-date-time-column 'Created (UTC)' --stripe-date-time-format '[year]-[month]-[day] [hour]:[minute]:[second]' --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity-created.csv
+    }
+    it "fails if no format fits" && {
+      WITH_SNAPSHOT="$snapshot/fail-stripe-date-time-format" \
+      expect_run ${WITH_ERROR} "$exe" merge-accounts --stripe-date-time-column 'Created (UTC)' --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity-created.csv
+    }
+  )
+  (with "a configuration file"
+    it "uses its values for the flags that weren't given" && {
+      WITH_SNAPSHOT="$snapshot/success-config.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --config $fixture/merge-accounts.ron -m 5 --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity-created.csv
+    }
+    (when "it has an invalid value"
+      workdir="$(mktemp -d)"
+      trap 'rm -rf "$workdir"' EXIT
+      echo "(merge_accounts: (github_delimiter: 'é'))" > "$workdir/stool.ron"
+      it "fails naming the key in the configuration file" && {
+        cd "$workdir"
+        WITH_SNAPSHOT="$snapshot/fail-config-invalid-value" \
+        expect_run ${WITH_ERROR} "$exe" merge-accounts --config stool.ron --github-activity $fixture/sponsors-2021.csv --stripe-activity $fixture/stripe-activity.csv
+      }
+    )
+  )
+  (with "a configuration file with profiles in the working directory"
+    workdir="$(mktemp -d)"
+    trap 'rm -rf "$workdir"' EXIT
+    cp "$fixture/profiles.toml" "$workdir/stool.toml"
+    cd "$workdir"
+    it "uses the values of the profile before the ones outside of it" && {
+      WITH_SNAPSHOT="$snapshot/success-config-profile.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --profile utc --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity-created.csv
+    }
+    it "fails if the profile doesn't exist" && {
+      WITH_SNAPSHOT="$snapshot/fail-config-unknown-profile" \
+      expect_run ${WITH_ERROR} "$exe" merge-accounts --profile eur --github-activity $fixture/sponsors-2021.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+  )
+  (with "two github account files and one stripe activity feed and an automatic window"
+    it "reports the chosen window and the lags it was chosen from" && {
+      WITH_SNAPSHOT="$snapshot/success-auto-window.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --auto-window 600 --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+  )
+  (with "two github account files and one stripe activity feed and a few windows to try"
+    it "prints the amount of paired and unpaired rows for each window" && {
+      WITH_SNAPSHOT="$snapshot/success-tune" \
+      expect_run ${SUCCESSFULLY} "$exe" tune --windows 1,10,60 --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+  )
+  (with "a few windows to try along with an output file"
+    it "fails naming the flag that has no effect" && {
+      WITH_SNAPSHOT="$snapshot/fail-tune-with-output-file" \
+      expect_run ${WITH_ERROR} "$exe" tune --windows 1,10 --matched matched.csv --github-activity $fixture/sponsors-2021.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+  )
+  (with "an invalid flag value"
+    it "fails naming the flag" && {
+      WITH_SNAPSHOT="$snapshot/fail-invalid-flag-value" \
+      expect_run ${WITH_ERROR} "$exe" merge-accounts --stripe-delimiter 'é' --github-activity $fixture/sponsors-2021.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+  )
+  (with "two github account files and one stripe activity feed with the GitHub transaction ID in a description"
+    it "pairs rows by ID first, and the remaining ones by time" && {
+      WITH_SNAPSHOT="$snapshot/success-matched-by-id.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --stripe-id-column Description --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity-with-ids.csv
+    }
+  )
+  (with "two github account files and one stripe activity feed with row IDs and an overrides file"
+    cd "$fixture"
+    it "pins and excludes rows before pairing the remaining ones, and reports overrides that reference nothing" && {
+      WITH_SNAPSHOT="$snapshot/success-overrides.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --overrides overrides.ron --stripe-date-time-column 'Created (UTC)' --stripe-date-time-format '[year]-[month]-[day] [hour]:[minute]:[second]' --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity-created.csv
+    }
+    (when "unpaired GitHub rows are written into their own file"
+      workdir="$(mktemp -d)"
+      trap 'rm -rf "$workdir"' EXIT
+      "$exe" merge-accounts --overrides overrides.ron --stripe-date-time-column 'Created (UTC)' --stripe-date-time-format '[year]-[month]-[day] [hour]:[minute]:[second]' --unmatched-github "$workdir/unmatched-github.csv" --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity-created.csv >/dev/null 2>&1
+      it "tells the rows excluded by an override apart from the others" && {
+        WITH_SNAPSHOT="$snapshot/success-overrides-unmatched-github.csv" \
+        expect_run ${SUCCESSFULLY} cat "$workdir/unmatched-github.csv"
+      }
+    )
+    (when "an override has a date-time that can't be parsed"
+      workdir="$(mktemp -d)"
+      trap 'rm -rf "$workdir"' EXIT
+      echo '(overrides: [UnmatchedGithubRow(transaction_id: "a"), UnmatchedStripeRow(stripe_row: DateTime(date_time: "yesterday-ish", amount: 1.0))])' > "$workdir/overrides.ron"
+      it "fails naming the file and the override" && {
+        cd "$workdir"
+        WITH_SNAPSHOT="$snapshot/fail-overrides-invalid-date-time" \
+        expect_run ${WITH_ERROR} "$exe" merge-accounts --overrides overrides.ron --github-activity $fixture/sponsors-2021.csv --stripe-activity $fixture/stripe-activity.csv
+      }
+    )
+  )
+  (with "two github account files and one stripe activity feed and a window of days and reference rates"
+    it "pairs rows whose amounts don't fit and warns about them" && {
+      WITH_SNAPSHOT="$snapshot/success-implausible-amounts-flagged.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts -m 400000 --reference-rates $fixture/reference-rates.csv --plausible-rates 0.95..1.05 --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+    it "only pairs rows whose amounts fit if these are rejected" && {
+      WITH_SNAPSHOT="$snapshot/success-implausible-amounts-rejected.csv" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts -m 400000 --reference-rates $fixture/reference-rates.csv --plausible-rates 0.95..1.05 --on-implausible-amount reject --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+  )
+
+  (with "two github account files and one stripe activity feed and a semicolon as output delimiter"
+    snapshot_file="$snapshot/success-input-file-produces-correct-output-with-output-delimiter.csv"
+    it "produces output separated by semicolons" && {
+      WITH_SNAPSHOT="$snapshot_file" \
+      expect_run ${SUCCESSFULLY} "$exe" merge-accounts --output-delimiter ';' --output-quoting non-numeric --github-activity $fixture/sponsors-2021.csv -g $fixture/sponsors-2022.csv --stripe-activity $fixture/stripe-activity.csv
+    }
+    (with_program xsv
+      it "produces a valid CSV file" && {
+        expect_run ${SUCCESSFULLY} xsv table --delimiter ';' "$snapshot_file"
+      }
+    )
+  )
 )
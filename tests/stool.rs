@@ -1,3 +1,956 @@
+mod merge {
+    use stool::merge::{
+        ConflictPolicy, FieldChange, Input, KeyMode, Location, Options, SchemaPolicy, SortOrder,
+        SortType,
+    };
+
+    #[test]
+    fn whole_row_key_only_collapses_exact_duplicates() {
+        let mut out = Vec::new();
+        let outcome = stool::merge(
+            [
+                Input::new(
+                    "a.csv",
+                    "Date,Time,Amount\n2023-01-10,10:00:00,5\n2023-01-10,10:00:00,7\n".as_bytes(),
+                ),
+                Input::new(
+                    "b.csv",
+                    "Date,Time,Amount\n2023-01-10,10:00:00,7\n2023-01-09,09:00:00,1\n".as_bytes(),
+                ),
+            ],
+            &[],
+            &mut out,
+            Options {
+                sort_column: "Date".into(),
+                key_mode: KeyMode::WholeRow,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Date,Time,Amount\n2023-01-09,09:00:00,1\n2023-01-10,10:00:00,5\n2023-01-10,10:00:00,7\n",
+            "rows with the same date and time are only duplicates if all fields match"
+        );
+        assert_eq!(outcome.num_conflicts, 0);
+    }
+
+    #[test]
+    fn whole_row_key_ignores_whitespace_around_fields_when_looking_for_conflicts() {
+        let mut out = Vec::new();
+        let outcome = stool::merge(
+            [
+                Input::new(
+                    "a.csv",
+                    "Date,Time,Amount\n2023-01-10,10:00:00,5\n".as_bytes(),
+                ),
+                Input::new(
+                    "b.csv",
+                    "Date,Time,Amount\n2023-01-10, 10:00:00 ,5\n".as_bytes(),
+                ),
+            ],
+            &[],
+            &mut out,
+            Options {
+                sort_column: "Date".into(),
+                key_mode: KeyMode::WholeRow,
+                conflict_policy: ConflictPolicy::Error,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Date,Time,Amount\n2023-01-10, 10:00:00 ,5\n",
+            "the last of the duplicates is kept"
+        );
+        assert_eq!(outcome.num_conflicts, 0);
+    }
+
+    #[test]
+    fn bounded_memory_produces_the_same_output() {
+        let inputs: Vec<String> = (0..4)
+            .map(|input| {
+                let mut csv = String::from("Key,Status,Amount\n");
+                for row in 0..80 {
+                    let key = (row * 7 + input * 13) % 60;
+                    let status = ["pending", "settled", "refunded"][(row + input) % 3];
+                    csv.push_str(&format!("k{key},{status},{}\n", (key * 31) % 17));
+                }
+                csv
+            })
+            .collect();
+        let merge = |max_records_in_memory, sort_order| {
+            let mut out = Vec::new();
+            let outcome = stool::merge(
+                inputs
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, csv)| Input::new(format!("{idx}.csv"), csv.as_bytes())),
+                &["Key"],
+                &mut out,
+                Options {
+                    sort_column: "Amount".into(),
+                    sort_type: SortType::Number {
+                        decimal_separator: '.',
+                    },
+                    sort_order,
+                    conflict_policy: ConflictPolicy::PreferByColumn {
+                        column: "Status".into(),
+                        values: vec!["pending".into(), "settled".into()],
+                    },
+                    report_conflicts: true,
+                    provenance: true,
+                    max_records_in_memory,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            (out, outcome.conflicts)
+        };
+        for sort_order in [SortOrder::Ascending, SortOrder::Descending] {
+            let expected = merge(None, sort_order);
+            for limit in [1, 3, 64, 1000] {
+                assert_eq!(
+                    merge(Some(limit), sort_order),
+                    expected,
+                    "output and conflicts are the same with a limit of {limit}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn provenance_columns_tell_where_rows_came_from() {
+        let mut out = Vec::new();
+        stool::merge(
+            [
+                Input::new(
+                    "january.csv",
+                    "Key,Status\na,pending\nb,settled\n".as_bytes(),
+                ),
+                Input::new(
+                    "february.csv",
+                    "Key,Status\nb,settled\nc,pending\nc,refunded\n".as_bytes(),
+                ),
+            ],
+            &["Key"],
+            &mut out,
+            Options {
+                sort_column: "Key".into(),
+                provenance: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Key,Status,Source File,Source Line,Seen In Files\n\
+             a,pending,january.csv,2,1\n\
+             b,settled,february.csv,2,2\n\
+             c,refunded,february.csv,4,1\n"
+        );
+    }
+
+    #[test]
+    fn source_lines_count_lines_ending_with_crlf_and_lines_within_fields() {
+        let mut out = Vec::new();
+        stool::merge(
+            Some(Input::new(
+                "crlf.csv",
+                "Key,Note\r\na,\"first\r\nsecond\"\r\nb,\r\n".as_bytes(),
+            )),
+            &["Key"],
+            &mut out,
+            Options {
+                sort_column: "Key".into(),
+                provenance: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Key,Note,Source File,Source Line,Seen In Files\n\
+             a,\"first\r\nsecond\",crlf.csv,2,1\n\
+             b,,crlf.csv,4,1\n"
+        );
+    }
+
+    #[test]
+    fn union_of_columns_lines_up_fields_by_name() {
+        let mut out = Vec::new();
+        let outcome = stool::merge(
+            [
+                Input::new("a.csv", "Key,Amount\na,1\nb,2\n".as_bytes()),
+                Input::new("b.csv", "VAT,Amount,Key\nx,3,b\ny,4,c\n".as_bytes()),
+            ],
+            &["Key"],
+            &mut out,
+            Options {
+                sort_column: "Key".into(),
+                schema: SchemaPolicy::Union,
+                report_conflicts: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Key,Amount,VAT\na,1,\nb,3,x\nc,4,y\n"
+        );
+        assert_eq!(outcome.missing_columns, [vec!["VAT".to_string()], vec![]]);
+        assert_eq!(
+            outcome.conflicts[0].changes.len(),
+            2,
+            "missing fields compare like empty ones"
+        );
+    }
+
+    #[test]
+    fn keep_first_on_conflict_and_report_it() {
+        let mut out = Vec::new();
+        let outcome = stool::merge(
+            [
+                Input::new("a.csv", "Key,Status\na,pending\nb,settled\n".as_bytes()),
+                Input::new("b.csv", "Key,Status\na,refunded\nb,settled\n".as_bytes()),
+            ],
+            &["Key"],
+            &mut out,
+            Options {
+                sort_column: "Key".into(),
+                conflict_policy: ConflictPolicy::KeepFirst,
+                report_conflicts: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Key,Status\na,pending\nb,settled\n"
+        );
+        assert_eq!(
+            outcome.num_conflicts, 1,
+            "identical rows don't count as conflict"
+        );
+        assert_eq!(outcome.conflicts[0].key, ["a"]);
+        assert_eq!(outcome.conflicts[0].current, Location { input: 1, line: 2 });
+        assert!(!outcome.conflicts[0].kept_current);
+        assert_eq!(
+            outcome.conflicts[0].changes,
+            [FieldChange {
+                column: "Status".into(),
+                previous: "pending".into(),
+                current: "refunded".into(),
+            }]
+        );
+    }
+
+    #[test]
+    fn sort_by_number_descending() {
+        let input = "Key,Amount\na,\"€1.000,50\"\nb,\"€8,75\"\nc,\"€10,00\"\nd,\n";
+        let mut out = Vec::new();
+        stool::merge(
+            Some(Input::new("input.csv", input.as_bytes())),
+            &["Key"],
+            &mut out,
+            Options {
+                sort_column: "Amount".into(),
+                sort_type: SortType::Number {
+                    decimal_separator: ',',
+                },
+                sort_order: SortOrder::Descending,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            std::str::from_utf8(&out).unwrap(),
+            "Key,Amount\na,\"€1.000,50\"\nc,\"€10,00\"\nb,\"€8,75\"\nd,\n",
+            "empty values sort first, and thus last when descending"
+        );
+    }
+
+    #[test]
+    fn unparseable_sort_values_are_an_error() {
+        let err = stool::merge(
+            Some(Input::new(
+                "input.csv",
+                "Key,Date\na,yesterday\n".as_bytes(),
+            )),
+            &["Key"],
+            Vec::new(),
+            Options {
+                sort_column: "Date".into(),
+                sort_type: SortType::DateTime,
+                ..Default::default()
+            },
+        )
+        .err()
+        .unwrap();
+        assert!(matches!(
+            err,
+            stool::merge::Error::ParseSortValue { line: 2, .. }
+        ));
+    }
+}
+
+mod merge_accounts {
+    use stool::merge::Input;
+    use stool::merge_accounts::overrides::{Unresolved, UnresolvedReason};
+    use stool::merge_accounts::{
+        AmountPolicy, AutoWindow, Candidate, ChangedPair, Explanation, ImplausiblePair, Matching,
+        Options, Rejection, Sink,
+    };
+
+    const GITHUB: &str = "Transaction ID,Transaction Date
+a,2022-01-10 10:00:00 -0500
+b,2022-01-10 14:59:58 +0000
+";
+    const STRIPE: &str = "Date,Time,Net
+\"January 10, 2022\",15:00:01 UTC,1
+\"January 10, 2022\",15:00:03 UTC,2
+";
+
+    /// Rows of the same time, of which only the first fits to both stripe rows by its amount.
+    const GITHUB_WITH_AMOUNTS: &str = "Transaction ID,Transaction Date,Processed Amount
+a,2022-01-10 10:00:00 -0500,$2.00
+b,2022-01-10 15:00:00 +0000,$1.00
+";
+
+    fn merge_accounts(matching: Matching) -> (String, Vec<ChangedPair>) {
+        let mut out = Vec::new();
+        let outcome = stool::merge_accounts(
+            Some(Input::new("github.csv", GITHUB_WITH_AMOUNTS.as_bytes())),
+            Some(Input::new("stripe.csv", STRIPE.as_bytes())),
+            Sink::Combined(&mut out),
+            Options {
+                max_after_seconds: 3,
+                matching,
+                plausible_rates: Some(0.5..=1.5),
+                on_implausible_amount: AmountPolicy::Reject,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        (String::from_utf8(out).unwrap(), outcome.changed_pairs)
+    }
+
+    fn at(second: i64) -> time::OffsetDateTime {
+        time::macros::datetime!(2022-01-10 15:00:00 UTC) + time::Duration::seconds(second)
+    }
+
+    #[test]
+    fn greedy_matching_lets_earlier_rows_take_the_best_candidate_and_appends_unmatched_stripe_rows()
+    {
+        let (out, changed_pairs) = merge_accounts(Matching::Greedy);
+        assert_eq!(
+            out,
+            "Transaction ID,Transaction Date,Processed Amount,Received Date,Distance [s],Date,Time,Net,Confidence,Gross [USD],Net [Local],Implied Rate,Fee [Local],Unmatched Reason
+a,2022-01-10 10:00:00 -0500,\"$2,00\",2022-01-10 15:00:01 +0000,1,\"January 10, 2022\",15:00:01 UTC,1,\"0,56\",\"2,00\",\"1,00\",\"0,5000\",,
+b,2022-01-10 15:00:00 +0000,\"$1,00\",,,,,,,,,,,
+,,,2022-01-10 15:00:03 +0000,,\"January 10, 2022\",15:00:03 UTC,2,,,,,,candidate taken by another row
+"
+        );
+        assert!(changed_pairs.is_empty());
+    }
+
+    #[test]
+    fn optimal_matching_pairs_as_many_rows_as_possible() {
+        let (out, changed_pairs) = merge_accounts(Matching::Optimal);
+        assert_eq!(
+            out,
+            "Transaction ID,Transaction Date,Processed Amount,Received Date,Distance [s],Date,Time,Net,Confidence,Gross [USD],Net [Local],Implied Rate,Fee [Local],Unmatched Reason
+a,2022-01-10 10:00:00 -0500,\"$2,00\",2022-01-10 15:00:03 +0000,3,\"January 10, 2022\",15:00:03 UTC,2,\"0,12\",\"2,00\",\"2,00\",\"1,0000\",,
+b,2022-01-10 15:00:00 +0000,\"$1,00\",2022-01-10 15:00:01 +0000,1,\"January 10, 2022\",15:00:01 UTC,1,\"0,75\",\"1,00\",\"1,00\",\"1,0000\",,
+"
+        );
+        assert_eq!(
+            changed_pairs,
+            [
+                ChangedPair {
+                    github_key: "a".into(),
+                    greedy: Some(at(1)),
+                    current: Some(at(3)),
+                },
+                ChangedPair {
+                    github_key: "b".into(),
+                    greedy: None,
+                    current: Some(at(1)),
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn greedy_matching_goes_by_the_time_of_github_rows_instead_of_their_order() {
+        let github = "Transaction ID,Transaction Date
+a,2022-01-10 10:00:01 -0500
+b,2022-01-10 15:00:00 +0000
+";
+        let stripe = "Date,Time,Net
+\"January 10, 2022\",15:00:02 UTC,1
+";
+        let mut matched = Vec::new();
+        stool::merge_accounts(
+            Some(Input::new("github.csv", github.as_bytes())),
+            Some(Input::new("stripe.csv", stripe.as_bytes())),
+            Sink::Split {
+                matched: Some(&mut matched),
+                unmatched_github: None,
+                unmatched_stripe: None,
+                rest: None,
+            },
+            Options {
+                max_after_seconds: 3,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let matched = String::from_utf8(matched).unwrap();
+        assert_eq!(
+            matched
+                .lines()
+                .nth(1)
+                .and_then(|line| line.split(',').next()),
+            Some("b"),
+            "the earlier row takes the stripe row even though it comes second"
+        );
+    }
+
+    #[test]
+    fn optimal_matching_falls_back_to_greedy_matching_for_too_many_rows_sharing_candidates() {
+        let num_rows = 300;
+        let mut github = String::from("Transaction ID,Transaction Date\n");
+        let mut stripe = String::from("Date,Time,Net\n");
+        for second in 0..num_rows {
+            let time = time::Time::MIDNIGHT + time::Duration::seconds(second);
+            github.push_str(&format!(
+                "t{second},2022-01-10 {:02}:{:02}:{:02} +0000\n",
+                time.hour(),
+                time.minute(),
+                time.second()
+            ));
+            stripe.push_str(&format!(
+                "\"January 10, 2022\",{:02}:{:02}:{:02} UTC,1\n",
+                time.hour(),
+                time.minute(),
+                time.second()
+            ));
+        }
+        let merge_accounts = |matching| {
+            let mut out = Vec::new();
+            let outcome = stool::merge_accounts(
+                Some(Input::new("github.csv", github.as_bytes())),
+                Some(Input::new("stripe.csv", stripe.as_bytes())),
+                Sink::Combined(&mut out),
+                Options {
+                    max_after_seconds: 60,
+                    matching,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            (out, outcome)
+        };
+        let (greedy, _) = merge_accounts(Matching::Greedy);
+        let (optimal, outcome) = merge_accounts(Matching::Optimal);
+        assert_eq!(optimal, greedy);
+        assert_eq!(outcome.num_greedy_github_rows, num_rows as usize);
+        assert!(outcome.changed_pairs.is_empty());
+    }
+
+    #[test]
+    fn github_rows_with_more_than_one_candidate_are_ambiguous() {
+        let outcome = stool::merge_accounts(
+            Some(Input::new("github.csv", GITHUB.as_bytes())),
+            Some(Input::new("stripe.csv", STRIPE.as_bytes())),
+            Sink::Combined(std::io::sink()),
+            Options {
+                max_after_seconds: 3,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            outcome.num_ambiguous_github_rows, 1,
+            "only the first row has both stripe rows within its window"
+        );
+    }
+
+    #[test]
+    fn stripe_rows_booked_before_the_github_row_are_paired_within_max_before_seconds() {
+        let merge_accounts = |max_before_seconds| {
+            let mut out = Vec::new();
+            stool::merge_accounts(
+                Some(Input::new(
+                    "github.csv",
+                    "Transaction ID,Transaction Date\na,2022-01-10 15:00:02 +0000\n".as_bytes(),
+                )),
+                Some(Input::new("stripe.csv", STRIPE.as_bytes())),
+                Sink::Combined(&mut out),
+                Options {
+                    max_before_seconds,
+                    max_after_seconds: 0,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            merge_accounts(0),
+            "Transaction ID,Transaction Date,Received Date,Distance [s],Date,Time,Net,Confidence,Gross [USD],Net [Local],Implied Rate,Fee [Local],Unmatched Reason
+a,2022-01-10 15:00:02 +0000,,,,,,,,,,,
+,,2022-01-10 15:00:01 +0000,,\"January 10, 2022\",15:00:01 UTC,1,,,,,,no GitHub row within window
+,,2022-01-10 15:00:03 +0000,,\"January 10, 2022\",15:00:03 UTC,2,,,,,,no GitHub row within window
+"
+        );
+        assert_eq!(
+            merge_accounts(1),
+            "Transaction ID,Transaction Date,Received Date,Distance [s],Date,Time,Net,Confidence,Gross [USD],Net [Local],Implied Rate,Fee [Local],Unmatched Reason
+a,2022-01-10 15:00:02 +0000,2022-01-10 15:00:01 +0000,-1,\"January 10, 2022\",15:00:01 UTC,1,\"0,25\",,\"1,00\",,,
+,,2022-01-10 15:00:03 +0000,,\"January 10, 2022\",15:00:03 UTC,2,,,,,,no GitHub row within window
+"
+        );
+    }
+
+    #[test]
+    fn greedy_matching_prefers_the_closest_stripe_row_over_an_earlier_one() {
+        let stripe = "Date,Time,Net
+\"January 10, 2022\",15:00:00 UTC,1
+\"January 10, 2022\",15:00:02 UTC,1
+";
+        let mut matched = Vec::new();
+        stool::merge_accounts(
+            Some(Input::new(
+                "github.csv",
+                "Transaction ID,Transaction Date\na,2022-01-10 15:00:02 +0000\n".as_bytes(),
+            )),
+            Some(Input::new("stripe.csv", stripe.as_bytes())),
+            Sink::Split {
+                matched: Some(&mut matched),
+                unmatched_github: None,
+                unmatched_stripe: None,
+                rest: None,
+            },
+            Options {
+                max_before_seconds: 2,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(matched).unwrap(),
+            "Transaction ID,Transaction Date,Received Date,Distance [s],Date,Time,Net,Confidence,Gross [USD],Net [Local],Implied Rate,Fee [Local]
+a,2022-01-10 15:00:02 +0000,2022-01-10 15:00:02 +0000,0,\"January 10, 2022\",15:00:02 UTC,1,\"0,30\",,\"1,00\",,
+"
+        );
+    }
+
+    #[test]
+    fn auto_window_is_chosen_from_the_lags_of_unambiguous_pairs() {
+        let mut out = Vec::new();
+        let outcome = stool::merge_accounts(
+            Some(Input::new(
+                "github.csv",
+                "Transaction ID,Transaction Date
+a,2022-01-10 15:00:00 +0000
+b,2022-01-10 15:01:40 +0000
+"
+                .as_bytes(),
+            )),
+            Some(Input::new(
+                "stripe.csv",
+                "Date,Time,Net
+\"January 10, 2022\",15:00:02 UTC,1
+\"January 10, 2022\",15:01:45 UTC,2
+"
+                .as_bytes(),
+            )),
+            Sink::Combined(&mut out),
+            Options {
+                max_after_seconds: 1,
+                auto_window_seconds: Some(60),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            outcome.auto_window,
+            Some(AutoWindow {
+                num_pairs: 2,
+                lag_percentiles: vec![(50, 2), (90, 5), (95, 5), (99, 5)],
+                max_after_seconds: 10,
+            })
+        );
+        assert_eq!(
+            outcome.num_matched, 2,
+            "the second pass uses the chosen window"
+        );
+    }
+
+    #[test]
+    fn explanations_judge_candidates_by_the_auto_window() {
+        let outcome = stool::merge_accounts(
+            Some(Input::new(
+                "github.csv",
+                "Transaction ID,Transaction Date
+a,2022-01-10 15:00:00 +0000
+"
+                .as_bytes(),
+            )),
+            Some(Input::new(
+                "stripe.csv",
+                "Date,Time,Net
+\"January 10, 2022\",15:00:02 UTC,1
+\"January 10, 2022\",15:00:30 UTC,2
+"
+                .as_bytes(),
+            )),
+            Sink::Combined(std::io::sink()),
+            Options {
+                max_after_seconds: 60,
+                auto_window_seconds: Some(20),
+                explain: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            outcome.auto_window.map(|window| window.max_after_seconds),
+            Some(4)
+        );
+        assert_eq!(
+            outcome.explanations[0].candidates,
+            [
+                Candidate {
+                    date_time: at(2),
+                    offset_seconds: 2,
+                    rejection: None,
+                },
+                Candidate {
+                    date_time: at(30),
+                    offset_seconds: 30,
+                    rejection: Some(Rejection::BeyondMaxDistance),
+                }
+            ],
+            "the row at 30s is beyond the chosen window, even though it is within the one of the options"
+        );
+    }
+
+    #[test]
+    fn explanations_tell_why_candidates_were_rejected() {
+        let outcome = stool::merge_accounts(
+            Some(Input::new("github.csv", GITHUB.as_bytes())),
+            Some(Input::new("stripe.csv", STRIPE.as_bytes())),
+            Sink::Combined(std::io::sink()),
+            Options {
+                max_after_seconds: 3,
+                explain: true,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let candidate = |second, offset_seconds, rejection| Candidate {
+            date_time: at(second),
+            offset_seconds,
+            rejection,
+        };
+        assert_eq!(
+            outcome.explanations,
+            [
+                Explanation {
+                    github_key: "a".into(),
+                    date_time: at(0),
+                    candidates: vec![
+                        candidate(1, 1, Some(Rejection::AlreadyConsumed)),
+                        candidate(3, 3, None)
+                    ],
+                },
+                Explanation {
+                    github_key: "b".into(),
+                    date_time: at(-2),
+                    candidates: vec![
+                        candidate(1, 3, None),
+                        candidate(3, 5, Some(Rejection::BeyondMaxDistance))
+                    ],
+                }
+            ]
+        );
+    }
+
+    #[test]
+    fn batches_pair_a_group_of_github_rows_with_one_stripe_row_of_their_summed_amount() {
+        let github = "Transaction ID,Transaction Date,Processed Amount
+a,2022-01-10 15:00:00 +0000,$2.00
+b,2022-01-10 15:00:10 +0000,$3.00
+c,2022-01-10 14:00:00 +0000,$10.00
+";
+        let stripe = "Date,Time,Net
+\"January 10, 2022\",15:00:30 UTC,\"€4,40\"
+";
+        let mut matched = Vec::new();
+        let outcome = stool::merge_accounts(
+            Some(Input::new("github.csv", github.as_bytes())),
+            Some(Input::new("stripe.csv", stripe.as_bytes())),
+            Sink::Split {
+                matched: Some(&mut matched),
+                unmatched_github: None,
+                unmatched_stripe: None,
+                rest: None,
+            },
+            Options {
+                max_after_seconds: 3,
+                batch_window_seconds: Some(60),
+                plausible_rates: Some(0.78..=0.98),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(matched).unwrap(),
+            "Transaction ID,Transaction Date,Processed Amount,Received Date,Distance [s],Group,Date,Time,Net,Confidence,Gross [USD],Net [Local],Implied Rate,Fee [Local]
+a,2022-01-10 15:00:00 +0000,\"$2,00\",2022-01-10 15:00:30 +0000,30,1,\"January 10, 2022\",15:00:30 UTC,\"€4,40\",\"0,51\",\"2,00\",\"1,76\",\"0,8800\",
+b,2022-01-10 15:00:10 +0000,\"$3,00\",2022-01-10 15:00:30 +0000,20,1,\"January 10, 2022\",15:00:30 UTC,\"€4,40\",\"0,67\",\"3,00\",\"2,64\",\"0,8800\",
+"
+        );
+        assert_eq!(
+            (
+                outcome.num_matched,
+                outcome.num_unmatched_github_rows,
+                outcome.num_unmatched_stripe_rows
+            ),
+            (2, 1, 0)
+        );
+    }
+
+    #[test]
+    fn batches_only_form_if_the_summed_amounts_convert_to_the_stripe_amount() {
+        let github = "Transaction ID,Transaction Date,Processed Amount
+a,2022-01-10 15:00:00 +0000,$10.00
+b,2022-01-10 15:00:10 +0000,$5.00
+";
+        for (net, num_matched) in [("\"€13,10\"", 2), ("\"€12,60\"", 0), ("\"€9,00\"", 0)] {
+            let stripe = format!(
+                "Date,Time,Net,Currency
+\"January 10, 2022\",15:00:30 UTC,{net},EUR
+"
+            );
+            let outcome = stool::merge_accounts(
+                Some(Input::new("github.csv", github.as_bytes())),
+                Some(Input::new("stripe.csv", stripe.as_bytes())),
+                Sink::Combined(std::io::sink()),
+                Options {
+                    max_after_seconds: 3,
+                    batch_window_seconds: Some(60),
+                    reference_rates: Some("tests/fixtures/reference-rates.csv".into()),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            assert_eq!(
+                outcome.num_matched, num_matched,
+                "$15.00 at 0.88 EUR per USD are €13,20, and {net} is a plausible amount, but only fits within the batch tolerance if it is close"
+            );
+        }
+    }
+
+    #[test]
+    fn stripe_date_times_in_one_column_are_parsed_with_the_first_format_that_fits() {
+        let stripe = "Created,Net
+2022-01-10 15:00:01,1
+2022-01-10T16:00:03+01:00,2
+";
+        let mut matched = Vec::new();
+        stool::merge_accounts(
+            Some(Input::new("github.csv", GITHUB.as_bytes())),
+            Some(Input::new("stripe.csv", stripe.as_bytes())),
+            Sink::Split {
+                matched: Some(&mut matched),
+                unmatched_github: None,
+                unmatched_stripe: None,
+                rest: None,
+            },
+            Options {
+                max_after_seconds: 5,
+                stripe_date_column: "Created".into(),
+                stripe_time_column: None,
+                stripe_date_time_formats: vec![
+                    "[year]-[month]-[day] [hour]:[minute]:[second]".into(),
+                    "[year]-[month]-[day]T[hour]:[minute]:[second][offset_hour]:[offset_minute]"
+                        .into(),
+                ],
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        let received_dates: Vec<_> = String::from_utf8(matched)
+            .unwrap()
+            .lines()
+            .skip(1)
+            .map(|line| line.split(',').nth(2).unwrap().to_owned())
+            .collect();
+        assert_eq!(
+            received_dates,
+            ["2022-01-10 15:00:03 +0000", "2022-01-10 15:00:01 +0000"],
+            "the second row is the earlier one"
+        );
+    }
+
+    #[test]
+    fn implausible_amounts_are_flagged_or_rejected() {
+        let github = "Transaction ID,Transaction Date,Processed Amount
+a,2022-01-10 15:00:00 +0000,$2.00
+";
+        let stripe = "Date,Time,Net,Currency
+\"January 10, 2022\",15:00:01 UTC,\"€8,75\",EUR
+\"January 10, 2022\",15:00:03 UTC,\"€1,74\",EUR
+";
+        let merge_accounts = |on_implausible_amount, reference_rates: Option<&str>| {
+            let mut unmatched_stripe = Vec::new();
+            let outcome = stool::merge_accounts(
+                Some(Input::new("github.csv", github.as_bytes())),
+                Some(Input::new("stripe.csv", stripe.as_bytes())),
+                Sink::Split {
+                    matched: None,
+                    unmatched_github: None,
+                    unmatched_stripe: Some(&mut unmatched_stripe),
+                    rest: None,
+                },
+                Options {
+                    max_after_seconds: 3,
+                    on_implausible_amount,
+                    reference_rates: reference_rates.map(Into::into),
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+            (outcome, String::from_utf8(unmatched_stripe).unwrap())
+        };
+
+        let (outcome, unmatched_stripe) = merge_accounts(
+            AmountPolicy::Flag,
+            Some("tests/fixtures/reference-rates.csv"),
+        );
+        assert_eq!(
+            outcome.implausible_pairs,
+            [ImplausiblePair {
+                github_key: "a".into(),
+                date_time: at(1),
+                rate: 4.375,
+            }]
+        );
+        assert_eq!(
+            unmatched_stripe,
+            "Date,Time,Net,Currency,Unmatched Reason
+\"January 10, 2022\",15:00:03 UTC,\"€1,74\",EUR,candidate taken by another row
+"
+        );
+
+        let (outcome, unmatched_stripe) = merge_accounts(
+            AmountPolicy::Reject,
+            Some("tests/fixtures/reference-rates.csv"),
+        );
+        assert!(outcome.implausible_pairs.is_empty());
+        assert_eq!(
+            unmatched_stripe,
+            "Date,Time,Net,Currency,Unmatched Reason
+\"January 10, 2022\",15:00:01 UTC,\"€8,75\",EUR,implausible amount
+"
+        );
+
+        let (outcome, _) = merge_accounts(AmountPolicy::Reject, None);
+        assert_eq!(
+            (outcome.implausible_pairs.len(), outcome.num_matched),
+            (0, 1),
+            "without reference rates or plausible rates, amounts aren't judged"
+        );
+    }
+
+    #[test]
+    fn reference_rates_with_three_or_four_decimals_are_read_with_their_decimal_point() {
+        let github = "Transaction ID,Transaction Date,Processed Amount
+a,2022-01-10 15:00:00 +0000,$10.00
+b,2022-01-10 16:00:00 +0000,$10.00
+";
+        let stripe = "Date,Time,Net,Currency
+\"January 10, 2022\",15:00:01 UTC,\"¥1,495\",JPY
+\"January 10, 2022\",16:00:01 UTC,\"CHF 8,81\",CHF
+";
+        let outcome = stool::merge_accounts(
+            Some(Input::new("github.csv", github.as_bytes())),
+            Some(Input::new("stripe.csv", stripe.as_bytes())),
+            Sink::Combined(std::io::sink()),
+            Options {
+                max_after_seconds: 3,
+                reference_rates: Some("tests/fixtures/reference-rates.csv".into()),
+                plausible_rates: Some(0.95..=1.05),
+                on_implausible_amount: AmountPolicy::Reject,
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            outcome.num_matched, 2,
+            "149.532 JPY and 0,8812 CHF per USD fit to the amounts"
+        );
+    }
+
+    #[test]
+    fn overrides_pin_or_exclude_rows_before_matching_and_unresolved_ones_are_reported() {
+        let overrides = tempfile::NamedTempFile::new().unwrap();
+        std::fs::write(
+            overrides.path(),
+            r#"Overrides(overrides: [
+    Pin(transaction_id: "a", stripe_row: Id("txn_2")),
+    UnmatchedGithubRow(transaction_id: "b"),
+    UnmatchedStripeRow(stripe_row: DateTime(date_time: "2022-01-10 15:00:01 +0000", amount: 1.0)),
+    Pin(transaction_id: "c", stripe_row: Id("txn_1")),
+    UnmatchedStripeRow(stripe_row: Id("txn_2")),
+])"#,
+        )
+        .unwrap();
+        let stripe = "Date,Time,Net,id
+\"January 10, 2022\",15:00:01 UTC,1,txn_1
+\"January 10, 2022\",15:00:03 UTC,2,txn_2
+";
+        let mut out = Vec::new();
+        let outcome = stool::merge_accounts(
+            Some(Input::new("github.csv", GITHUB.as_bytes())),
+            Some(Input::new("stripe.csv", stripe.as_bytes())),
+            Sink::Combined(&mut out),
+            Options {
+                max_after_seconds: 3,
+                overrides: Some(overrides.path().into()),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Transaction ID,Transaction Date,Received Date,Distance [s],Matched By,Date,Time,Net,id,Confidence,Gross [USD],Net [Local],Implied Rate,Fee [Local],Unmatched Reason
+a,2022-01-10 10:00:00 -0500,2022-01-10 15:00:03 +0000,3,override,\"January 10, 2022\",15:00:03 UTC,2,txn_2,\"1,00\",,\"2,00\",,,
+b,2022-01-10 14:59:58 +0000,,,,,,,,,,,,,intentionally unmatched
+,,2022-01-10 15:00:01 +0000,,,\"January 10, 2022\",15:00:01 UTC,1,txn_1,,,,,,intentionally unmatched
+"
+        );
+        assert_eq!(
+            outcome.unresolved_overrides,
+            [
+                Unresolved {
+                    index: 3,
+                    reason: UnresolvedReason::NoGithubRow,
+                },
+                Unresolved {
+                    index: 4,
+                    reason: UnresolvedReason::AlreadyReferenced,
+                }
+            ]
+        );
+    }
+}
+
 mod sle {
     use stool::sle::{Engine, Operation, Rule, Statement};
 
@@ -79,3 +1032,47 @@ fn normalize_number() {
         );
     }
 }
+
+#[test]
+fn parse_number() {
+    for (input, expected) in [
+        ("$10.00", Some(10.0)),
+        ("$1,000.00", Some(1000.0)),
+        ("€8,75", Some(8.75)),
+        (" €1.000,00 ", Some(1000.0)),
+        ("-€4,37", Some(-4.37)),
+        ("", None),
+    ] {
+        assert_eq!(stool::parse_number(input.as_bytes()), expected, "{input}");
+    }
+    for (input, expected) in [
+        ("1.5", Some(1.5)),
+        ("0,88", Some(0.88)),
+        ("0.921", Some(0.921)),
+        ("149,532", Some(149.532)),
+        ("12.345", Some(12.345)),
+        (" 0.8812 ", Some(0.8812)),
+        ("1.234,5", None),
+        ("", None),
+    ] {
+        assert_eq!(
+            stool::parse_rate(input.as_bytes()),
+            expected,
+            "a lone separator is the decimal point of a rate: {input}"
+        );
+    }
+}
+
+#[test]
+fn format_number() {
+    for (input, decimals, expected) in [
+        (10.0, 2, "10,00"),
+        (1000.0, 2, "1.000,00"),
+        (1234567.891, 2, "1.234.567,89"),
+        (-4.375, 4, "-4,3750"),
+        (-0.001, 2, "0,00"),
+        (123.0, 0, "123"),
+    ] {
+        assert_eq!(stool::format_number(input, decimals, '.', ','), expected);
+    }
+}